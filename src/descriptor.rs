@@ -0,0 +1,97 @@
+//! A description of a tunnel's configuration, for handing its fd to another
+//! process.
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// The configuration of a tunnel at the time it was described, as returned
+/// by `UtunStream::describe`.
+///
+/// An fd passed over `SCM_RIGHTS` arrives without any of this, so a
+/// privileged process that creates and configures the tunnel can send the
+/// descriptor alongside it. It converts to and from a single line of text
+/// with `Display` and `FromStr`:
+///
+/// ```text
+/// name=utun3 mtu=1400 flags=0x51 packet_info=1 addresses=10.0.0.1,fe80::1
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelDescriptor {
+    /// The interface name.
+    pub name: String,
+    /// The interface MTU.
+    pub mtu: u32,
+    /// The IPv4 and IPv6 addresses configured on the interface.
+    pub addresses: Vec<IpAddr>,
+    /// The interface flags (`IFF_UP`, `IFF_RUNNING`, ...).
+    pub flags: i16,
+    /// Whether packets are framed with a packet information header.
+    pub packet_info: bool,
+}
+
+impl fmt::Display for TunnelDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "name={} mtu={} flags={:#x} packet_info={} addresses=",
+               self.name, self.mtu, self.flags, self.packet_info as u8)?;
+        for (i, addr) in self.addresses.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TunnelDescriptor {
+    type Err = io::Error;
+
+    /// Parses the format written by `Display`, returning an `InvalidData`
+    /// error if a field is missing or malformed.
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("bad tunnel descriptor: {}", what));
+
+        let (mut name, mut mtu, mut flags, mut packet_info, mut addresses) =
+            (None, None, None, None, None);
+        for field in s.split_whitespace() {
+            let mut kv = field.splitn(2, '=');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(invalid(field)),
+            };
+            match key {
+                "name" if !value.is_empty() => name = Some(value.to_string()),
+                "mtu" => mtu = Some(value.parse().map_err(|_| invalid(field))?),
+                "flags" => {
+                    let hex = value.trim_start_matches("0x");
+                    let bits = u16::from_str_radix(hex, 16).map_err(|_| invalid(field))?;
+                    flags = Some(bits as i16);
+                }
+                "packet_info" => packet_info = match value {
+                    "0" => Some(false),
+                    "1" => Some(true),
+                    _ => return Err(invalid(field)),
+                },
+                "addresses" => {
+                    let addrs = value.split(',')
+                        .filter(|addr| !addr.is_empty())
+                        .map(|addr| addr.parse().map_err(|_| invalid(field)))
+                        .collect::<io::Result<Vec<IpAddr>>>()?;
+                    addresses = Some(addrs);
+                }
+                _ => return Err(invalid(field)),
+            }
+        }
+
+        Ok(TunnelDescriptor {
+            name: name.ok_or_else(|| invalid("missing name"))?,
+            mtu: mtu.ok_or_else(|| invalid("missing mtu"))?,
+            addresses: addresses.ok_or_else(|| invalid("missing addresses"))?,
+            flags: flags.ok_or_else(|| invalid("missing flags"))?,
+            packet_info: packet_info.ok_or_else(|| invalid("missing packet_info"))?,
+        })
+    }
+}
@@ -0,0 +1,56 @@
+//! The byte-level framing of tunnel packets, usable without an fd.
+//!
+//! Everything here is plain byte manipulation on top of `core`, so it can be
+//! used to prepare or inspect framed packets anywhere, such as a capture
+//! taken on another host.
+
+use IpVersion;
+
+/// The length of the header the kernel puts in front of each packet.
+pub const HEADER_LEN: usize = 4;
+
+/// The header formats a tunnel device frames packets with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HeaderFormat {
+    /// The Linux packet information header: 16 bits of flags, then the
+    /// packet's ethertype.
+    PacketInfo,
+    /// The macOS utun header: the packet's Darwin address family as a
+    /// big-endian `u32`.
+    AddressFamily,
+}
+
+impl HeaderFormat {
+    /// The format of the platform being compiled for.
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub const NATIVE: HeaderFormat = HeaderFormat::AddressFamily;
+    /// The format of the platform being compiled for.
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    pub const NATIVE: HeaderFormat = HeaderFormat::PacketInfo;
+}
+
+/// Returns the IP version in the first nibble of `packet`, if it's IPv4 or
+/// IPv6.
+pub fn ip_version(packet: &[u8]) -> Option<IpVersion> {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => Some(IpVersion::V4),
+        Some(6) => Some(IpVersion::V6),
+        _ => None,
+    }
+}
+
+/// Returns the header that frames a packet of the given version in `format`.
+pub fn header_for(format: HeaderFormat, version: IpVersion) -> [u8; HEADER_LEN] {
+    match (format, version) {
+        (HeaderFormat::PacketInfo, IpVersion::V4) => [0, 0, 0x08, 0x00],
+        (HeaderFormat::PacketInfo, IpVersion::V6) => [0, 0, 0x86, 0xdd],
+        (HeaderFormat::AddressFamily, IpVersion::V4) => 2u32.to_be_bytes(),
+        (HeaderFormat::AddressFamily, IpVersion::V6) => 30u32.to_be_bytes(),
+    }
+}
+
+/// Returns the packet in the framed packet `framed`, or `None` if it's too
+/// short to hold a header.
+pub fn strip_header(framed: &[u8]) -> Option<&[u8]> {
+    framed.get(HEADER_LEN..)
+}
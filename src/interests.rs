@@ -0,0 +1,21 @@
+//! Interest presets for registering a `UtunStream` with mio.
+//!
+//! mio's `Ready` has no `const` constructors, so these are functions rather
+//! than constants.
+
+use mio::Ready;
+
+/// Readable interest, for a stream that only receives packets.
+pub fn read() -> Ready {
+    Ready::readable()
+}
+
+/// Writable interest, for a stream that only sends packets.
+pub fn write() -> Ready {
+    Ready::writable()
+}
+
+/// Readable and writable interest, which suits most tunnels.
+pub fn read_write() -> Ready {
+    Ready::readable() | Ready::writable()
+}
@@ -1,21 +1,71 @@
 //! MIO bindings for Unix Domain Sockets
+//!
+//! Everything built on the tunnel fd needs a unix target and `std`. The
+//! `framing` module and `IpVersion` need neither, and are all that's left
+//! on other targets or with the default `std` feature turned off.
 
-#![cfg(unix)]
+#![cfg_attr(not(feature = "std"), no_std)]
 #![doc(html_root_url = "https://docs.rs/mio-utun/0.6")]
 
+#[cfg(all(unix, feature = "std"))]
 #[macro_use] extern crate nix;
+#[cfg(all(unix, feature = "std"))]
 extern crate libc;
+#[cfg(all(unix, feature = "std"))]
 extern crate mio;
 
+pub mod framing;
 
-#[cfg(all(unix, any(target_os = "macos", target_os = "ios")))]
+#[cfg(all(unix, feature = "std"))]
+mod descriptor;
+#[cfg(all(unix, feature = "std"))]
+pub use descriptor::TunnelDescriptor;
+#[cfg(all(unix, feature = "std"))]
+pub mod interests;
+#[cfg(all(unix, feature = "std"))]
+pub mod packet;
+#[cfg(all(unix, feature = "std"))]
+mod pool;
+#[cfg(all(unix, feature = "std"))]
+pub use pool::PacketBuf;
+#[cfg(all(unix, feature = "std"))]
+mod ratelimit;
+#[cfg(all(unix, feature = "std"))]
+pub use ratelimit::{Budget, RateLimited};
+#[cfg(all(unix, feature = "std"))]
+mod reconnect;
+#[cfg(all(unix, feature = "std"))]
+pub use reconnect::ReconnectingUtun;
+#[cfg(all(unix, feature = "std"))]
+mod stream;
+#[cfg(all(unix, feature = "std"))]
+pub use stream::{macos_header_family, queue_stats, self_test, validate_name, with_tunnel, DecapReader,
+                 QueueStat, RecvInfo, Registration, UnknownPacketPolicy};
+#[cfg(all(unix, feature = "std"))]
+use stream::{errno_of, io_error, Counters};
+// The ring mmap offsets don't fit a 32-bit `off_t`.
+#[cfg(all(feature = "std", target_os = "linux", target_pointer_width = "64"))]
+mod xsk;
+#[cfg(all(feature = "std", target_os = "linux", target_pointer_width = "64"))]
+pub use xsk::{XskConfig, XskDesc, XskSocket};
+
+#[cfg(all(unix, feature = "std", any(target_os = "macos", target_os = "ios")))]
 pub mod macos;
-#[cfg(all(unix, any(target_os = "macos", target_os = "ios")))]
-pub use macos::UtunStream;
+#[cfg(all(unix, feature = "std", any(target_os = "macos", target_os = "ios")))]
+pub use macos::{header_for, interface_exists, UtunBuilder, UtunStream, HEADER_LEN};
 
-#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+#[cfg(all(unix, feature = "std", not(any(target_os = "macos", target_os = "ios"))))]
 extern crate byteorder;
-#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+#[cfg(all(unix, feature = "std", not(any(target_os = "macos", target_os = "ios"))))]
 pub mod linux;
-#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
-pub use linux::UtunStream;
+#[cfg(all(unix, feature = "std", not(any(target_os = "macos", target_os = "ios"))))]
+pub use linux::{header_for, interface_exists, UtunBuilder, UtunStream, HEADER_LEN};
+
+/// The IP version of a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpVersion {
+    /// IPv4.
+    V4,
+    /// IPv6.
+    V6,
+}
@@ -1,7 +1,6 @@
 //! MIO bindings for Unix Domain Sockets
 
 #![cfg(unix)]
-#![doc(html_root_url = "https://docs.rs/mio-utun/0.6")]
 
 use libc;
 
@@ -12,33 +11,212 @@ use mio::event::Evented;
 use mio::{Poll, Token, Ready, PollOpt};
 
 use nix;
-use nix::errno::Errno;
 use nix::sys::stat::Mode;
 use nix::unistd::{close, read, write};
+use nix::sys::uio::{readv, IoVec};
 use nix::fcntl::{open, OFlag};
-use nix::sys::socket::{Shutdown, shutdown};
+use nix::poll::{poll, EventFlags, PollFd};
+use nix::sys::socket::{AddressFamily, SockFlag, SockType, Shutdown, socket, shutdown};
 
+use std::ffi::CString;
+use std::fs;
 use std::mem;
+use std::ptr;
+use framing::{self, HeaderFormat};
+use {errno_of, io_error, validate_name, Counters, IpVersion, QueueStat, RecvInfo, UnknownPacketPolicy};
+use pool::BufferPool;
 use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
 use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 
 /// The primary class for this crate, a stream of tunneled traffic.
 #[derive(Debug)]
 pub struct UtunStream {
     fd: RawFd,
+    packet_info: bool,
+    tap: bool,
+    vnet_hdr: bool,
+    counters: Counters,
+    pool: Option<BufferPool>,
+    discard_writes: bool,
+    unknown_policy: UnknownPacketPolicy,
+    keepalive: Option<(SocketAddr, SocketAddr)>,
+    last_ifreq: [u8; 40],
+}
+
+/// The operational state of an interface, as returned by
+/// `UtunStream::oper_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OperState {
+    /// The state can't be determined, which is usual for tun devices.
+    Unknown,
+    /// A component of the interface, typically hardware, is missing.
+    NotPresent,
+    /// The interface can't pass packets.
+    Down,
+    /// An interface this one is stacked on is down.
+    LowerLayerDown,
+    /// The interface is in a test mode.
+    Testing,
+    /// The interface is up but waiting for an external event.
+    Dormant,
+    /// The interface can pass packets.
+    Up,
+}
+
+/// Options which can be used to configure how a tun device is opened.
+///
+/// `UtunStream::connect` is equivalent to `UtunBuilder::new(name).connect()`.
+#[derive(Debug, Clone)]
+pub struct UtunBuilder {
+    name: String,
+    packet_info: bool,
+    tap: bool,
+    vnet_hdr: bool,
+    nonblocking: bool,
+    device_path: PathBuf,
+    buffer_pool: Option<(usize, usize)>,
 }
 
 pub const IFNAMSIZ: usize = 16;
 
+/// The tun clone device `UtunBuilder::connect` opens by default.
+#[cfg(target_os = "android")]
+const DEFAULT_DEVICE_PATH: &str = "/dev/tun";
+#[cfg(not(target_os = "android"))]
+const DEFAULT_DEVICE_PATH: &str = "/dev/net/tun";
+
 pub const IFF_UP:      i16 = 0x1;
 pub const IFF_RUNNING: i16 = 0x40;
+pub const IFF_NOARP:   i16 = 0x80;
+pub const IFF_PROMISC: i16 = 0x100;
 
 pub const IFF_TUN:   i16 = 0x0001;
+pub const IFF_TAP:   i16 = 0x0002;
 pub const IFF_NO_PI: i16 = 0x1000;
+pub const IFF_VNET_HDR: i16 = 0x4000;
+
+/// Set in the packet information flags when a read's buffer was too small
+/// for the packet.
+pub const TUN_PKT_STRIP: u16 = 0x0001;
+
+/// The length of the `virtio_net_hdr` that precedes each packet on a device
+/// opened with `UtunBuilder::vnet_hdr`.
+pub const VNET_HDR_LEN: usize = 10;
 
 ioctl!(write_ptr tunsetiff with b'T', 202; i32);
 ioctl!(read tungetiff with b'T', 210; u32);
+// Declared as a read by the kernel, though it reads the program fd from us.
+ioctl!(read tunsetsteeringebpf with b'T', 224; libc::c_int);
+ioctl!(bad read siocgifflags with libc::SIOCGIFFLAGS; ifreq);
+ioctl!(bad write_ptr siocsifflags with libc::SIOCSIFFLAGS; ifreq);
+ioctl!(bad read siocgifmtu with libc::SIOCGIFMTU; ifreq);
+ioctl!(bad write_ptr siocsifmtu with libc::SIOCSIFMTU; ifreq);
+ioctl!(bad read siocgifaddr with libc::SIOCGIFADDR; ifreq);
+ioctl!(bad write_ptr siocsifaddr with libc::SIOCSIFADDR; ifreq);
+ioctl!(bad read siocgifnetmask with libc::SIOCGIFNETMASK; ifreq);
+ioctl!(bad write_ptr siocsifnetmask with libc::SIOCSIFNETMASK; ifreq);
+
+/// `struct ifreq`, the argument to `TUNSETIFF` and the interface ioctls.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ifreq {
+    pub ifr_name: [u8; IFNAMSIZ],
+    pub ifr_ifru: ifr_ifru,
+}
+
+/// The `ifr_ifru` union of `struct ifreq`, with the members this crate uses.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union ifr_ifru {
+    pub ifru_addr: libc::sockaddr,
+    pub ifru_flags: libc::c_short,
+    pub ifru_mtu: libc::c_int,
+    /// Stands in for `struct ifmap`, the largest member, which sets the size
+    /// of the union.
+    pub ifru_map: [libc::c_ulong; 3],
+}
+
+impl ifreq {
+    /// Returns a zeroed `ifreq` for the interface `name`, which must be
+    /// shorter than `IFNAMSIZ`.
+    fn new(name: &str) -> ifreq {
+        let mut req: ifreq = unsafe { mem::zeroed() };
+        req.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+        req
+    }
+
+    /// Returns the raw bytes of the struct, zero-padded to 40 bytes where
+    /// it's smaller (on 32-bit ABIs).
+    fn to_bytes(self) -> [u8; 40] {
+        let mut bytes = [0u8; 40];
+        let len = mem::size_of::<ifreq>().min(bytes.len());
+        unsafe { ptr::copy_nonoverlapping(&self as *const ifreq as *const u8, bytes.as_mut_ptr(), len) };
+        bytes
+    }
+}
+
+/// The length of the packet information header prepended to each packet.
+pub const HEADER_LEN: usize = framing::HEADER_LEN;
+
+/// Returns the packet information header written ahead of a packet of the
+/// given version: zeroed flags followed by its big-endian ethertype.
+pub fn header_for(version: IpVersion) -> [u8; HEADER_LEN] {
+    framing::header_for(HeaderFormat::PacketInfo, version)
+}
+
+/// Returns whether a network interface called `name` exists, by checking
+/// for `/sys/class/net/<name>`.
+///
+/// Invalid names, as `validate_name` judges them, never exist.
+pub fn interface_exists(name: &str) -> bool {
+    validate_name(name).is_ok() && Path::new("/sys/class/net").join(name).exists()
+}
+
+/// Returns the `epoll_event` flags to add a tunnel fd to a native epoll set
+/// with, for event loops that don't go through mio.
+///
+/// The fds are registered edge-triggered, as mio does: after a readiness
+/// event, read (or write) until `WouldBlock` before waiting again, or later
+/// packets can sit unnoticed in the queue.
+pub fn epoll_events(interest_read: bool, interest_write: bool) -> u32 {
+    let mut events = libc::EPOLLET;
+    if interest_read {
+        events |= libc::EPOLLIN;
+    }
+    if interest_write {
+        events |= libc::EPOLLOUT;
+    }
+    events as u32
+}
+
+/// Sets the firewall mark (`SO_MARK`) on `socket`, so that policy routing can
+/// keep the traffic it sends from being routed back into the tunnel.
+///
+/// The tun fd itself is a character device and can't carry a mark; apply it
+/// to the socket that carries the encapsulated traffic instead. Requires
+/// `CAP_NET_ADMIN`.
+pub fn set_fwmark<S: AsRawFd>(socket: &S, mark: u32) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const u32 as *const libc::c_void,
+            mem::size_of::<u32>() as libc::socklen_t)
+    };
+
+    if res != 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
 
 impl UtunStream {
     /// Create a new TCP stream and issue a non-blocking connect to the
@@ -51,21 +229,36 @@ impl UtunStream {
     /// `TcpStream::connect_stream` to transfer ownership into mio and schedule
     /// the connect operation.
     pub fn connect(name: &str) -> io::Result<Self> {
-        let fd = open("/dev/net/tun", OFlag::O_RDWR | OFlag::O_NONBLOCK, Mode::empty())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-        let mut req = [0u8; 40]; // sizeof(struct ifreq)
-        if name.len() > (IFNAMSIZ - 1) {
-            return Err(io::ErrorKind::AddrNotAvailable.into())
-        }
-
-        req[..name.len()].copy_from_slice(name.as_bytes());
-        NativeEndian::write_i16(&mut req[16..], IFF_TUN);
+        UtunBuilder::new(name).connect()
+    }
 
-        unsafe { tunsetiff(fd, &mut req as *mut _ as *mut _) }
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    /// Creates the interface `name`, sets its MTU and IPv4 address, and
+    /// brings it up.
+    ///
+    /// If any step fails the interface's flags, MTU and IPv4 address are
+    /// restored to what they were before the error is returned. That only
+    /// matters for a persistent interface, which outlives the stream; any
+    /// other goes away with the fd.
+    pub fn setup(name: &str, addr: Ipv4Addr, netmask: Ipv4Addr, mtu: u32) -> io::Result<Self> {
+        let stream = UtunStream::connect(name)?;
+        let flags = stream.flags()?;
+        let old_mtu = stream.mtu()?;
+        let old_ipv4 = stream.ipv4_address()?;
 
-        return Ok(UtunStream { fd })
+        let res = stream.set_mtu(mtu)
+            .and_then(|_| stream.set_ipv4_address(addr, netmask))
+            .and_then(|_| stream.up());
+        if let Err(e) = res {
+            let _ = stream.set_flags(flags);
+            let _ = stream.set_mtu(old_mtu);
+            match old_ipv4 {
+                Some((addr, netmask)) => { let _ = stream.set_ipv4_address(addr, netmask); }
+                // Setting the unspecified address removes the address.
+                None => { let _ = stream.set_ipv4_address(Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED); }
+            }
+            return Err(e);
+        }
+        Ok(stream)
     }
 
     /// Returns the name of the interface as assigned by the OS.
@@ -85,6 +278,500 @@ impl UtunStream {
         }
     }
 
+    /// Returns the index of the tunnel's interface, as used by netlink and
+    /// by sockets that bind to a device, such as AF_XDP.
+    pub fn ifindex(&self) -> io::Result<u32> {
+        let name = CString::new(self.name()?)?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => Err(io::Error::last_os_error()),
+            index => Ok(index),
+        }
+    }
+
+    /// Returns the interface flags (`IFF_UP`, `IFF_RUNNING`, ...) as reported
+    /// by `SIOCGIFFLAGS`.
+    pub fn flags(&self) -> io::Result<i16> {
+        let req = self.if_ioctl(|_| {}, siocgifflags)?;
+        Ok(unsafe { req.ifr_ifru.ifru_flags })
+    }
+
+    /// Replaces the interface flags with `flags` (`SIOCSIFFLAGS`).
+    fn set_flags(&self, flags: i16) -> io::Result<()> {
+        self.if_ioctl(|req| req.ifr_ifru.ifru_flags = flags,
+                      |sock, req| unsafe { siocsifflags(sock, req) })
+            .map(|_| ())
+    }
+
+    /// Brings the interface up by setting `IFF_UP`.
+    pub fn up(&self) -> io::Result<()> {
+        let flags = self.flags()?;
+        self.set_flags(flags | IFF_UP)
+    }
+
+    /// Takes the interface down by clearing `IFF_UP`.
+    pub fn down(&self) -> io::Result<()> {
+        let flags = self.flags()?;
+        self.set_flags(flags & !IFF_UP)
+    }
+
+    /// Sets the MTU of the interface (`SIOCSIFMTU`).
+    pub fn set_mtu(&self, mtu: u32) -> io::Result<()> {
+        self.if_ioctl(|req| req.ifr_ifru.ifru_mtu = mtu as libc::c_int,
+                      |sock, req| unsafe { siocsifmtu(sock, req) })
+            .map(|_| ())
+    }
+
+    /// Sets the IPv4 address and netmask of the interface (`SIOCSIFADDR` and
+    /// `SIOCSIFNETMASK`), replacing any address it had.
+    pub fn set_ipv4_address(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+        self.if_ioctl(|req| req.ifr_ifru.ifru_addr = sockaddr_in(addr),
+                      |sock, req| unsafe { siocsifaddr(sock, req) })?;
+        self.if_ioctl(|req| req.ifr_ifru.ifru_addr = sockaddr_in(netmask),
+                      |sock, req| unsafe { siocsifnetmask(sock, req) })
+            .map(|_| ())
+    }
+
+    /// Returns the IPv4 address and netmask of the interface (`SIOCGIFADDR`
+    /// and `SIOCGIFNETMASK`), or `None` if it has no IPv4 address.
+    fn ipv4_address(&self) -> io::Result<Option<(Ipv4Addr, Ipv4Addr)>> {
+        let addr = match self.if_ioctl(|_| {}, siocgifaddr) {
+            Ok(req) => ipv4_of(unsafe { req.ifr_ifru.ifru_addr }),
+            Err(ref e) if errno_of(e) == Some(libc::EADDRNOTAVAIL) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let req = self.if_ioctl(|_| {}, siocgifnetmask)?;
+        Ok(Some((addr, ipv4_of(unsafe { req.ifr_ifru.ifru_addr }))))
+    }
+
+    /// Returns whether ARP is enabled on the interface, i.e. whether
+    /// `IFF_NOARP` is clear.
+    pub fn arp(&self) -> io::Result<bool> {
+        Ok(self.flags()? & IFF_NOARP == 0)
+    }
+
+    /// Enables or disables ARP on the interface by clearing or setting
+    /// `IFF_NOARP`, as `ip link set arp on|off` does.
+    ///
+    /// A point-to-point tunnel has no use for ARP. Needs the privileges to
+    /// configure the interface.
+    pub fn set_arp(&self, enabled: bool) -> io::Result<()> {
+        let flags = self.flags()?;
+        self.set_flags(if enabled { flags & !IFF_NOARP } else { flags | IFF_NOARP })
+    }
+
+    /// Returns whether a TAP interface is in promiscuous mode (`IFF_PROMISC`).
+    ///
+    /// Returns an `Unsupported` error on a TUN device.
+    pub fn promiscuous(&self) -> io::Result<bool> {
+        if !self.tap {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "not a TAP device"));
+        }
+        Ok(self.flags()? & IFF_PROMISC != 0)
+    }
+
+    /// Puts a TAP interface into or out of promiscuous mode, so that it
+    /// receives frames addressed to any MAC, such as all the traffic on a
+    /// bridge.
+    ///
+    /// Returns an `Unsupported` error on a TUN device, which has no link
+    /// layer.
+    pub fn set_promiscuous(&self, on: bool) -> io::Result<()> {
+        if !self.tap {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "not a TAP device"));
+        }
+        let flags = self.flags()?;
+        self.set_flags(if on { flags | IFF_PROMISC } else { flags & !IFF_PROMISC })
+    }
+
+    /// Returns the MTU of the interface.
+    pub fn mtu(&self) -> io::Result<u32> {
+        let req = self.if_ioctl(|_| {}, siocgifmtu)?;
+        Ok(unsafe { req.ifr_ifru.ifru_mtu } as u32)
+    }
+
+    /// Builds an `ifreq` for this interface, filled in by `fill`, and issues
+    /// the interface ioctl `f` with it on a throwaway socket, returning the
+    /// `ifreq` as the kernel left it.
+    fn if_ioctl<F>(&self, fill: F,
+                   f: unsafe fn(libc::c_int, *mut ifreq) -> nix::Result<libc::c_int>)
+                   -> io::Result<ifreq>
+        where F: FnOnce(&mut ifreq)
+    {
+        let mut req = ifreq::new(&self.name()?);
+        fill(&mut req);
+
+        let sock = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::empty(), None)
+            .map_err(io::Error::other)?;
+        let res = unsafe { f(sock, &mut req) };
+        let _ = close(sock);
+
+        res.map(|_| req).map_err(io::Error::other)
+    }
+
+    /// Returns the raw `ifreq` the device was set up with: the one passed to
+    /// `TUNSETIFF` as the kernel left it, or for an adopted fd the one read
+    /// back with `TUNGETIFF`. For debugging.
+    pub fn last_ifreq(&self) -> [u8; 40] {
+        self.last_ifreq
+    }
+
+    /// Blocks until the kernel reports the interface as `IFF_RUNNING`.
+    ///
+    /// Returns an error of kind `TimedOut` if the flag is still unset after
+    /// `timeout`.
+    pub fn wait_until_running(&self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.flags()? & IFF_RUNNING != 0 {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Returns whether the interface has carrier, as reported by
+    /// `/sys/class/net/<name>/carrier`.
+    ///
+    /// An interface that is down is reported as having no carrier.
+    pub fn carrier(&self) -> io::Result<bool> {
+        let path = format!("/sys/class/net/{}/carrier", self.name()?);
+        match fs::read_to_string(path) {
+            Ok(s) => Ok(s.trim() == "1"),
+            // The kernel refuses to report carrier for a down interface.
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the operational state of the interface (RFC 2863), as
+    /// reported by `/sys/class/net/<name>/operstate`.
+    ///
+    /// A tun interface that is up usually reports `Unknown`, since it has no
+    /// link layer to report on, and `Down` while no queue is attached.
+    pub fn oper_state(&self) -> io::Result<OperState> {
+        let path = format!("/sys/class/net/{}/operstate", self.name()?);
+        let state = match fs::read_to_string(path)?.trim() {
+            "unknown" => OperState::Unknown,
+            "notpresent" => OperState::NotPresent,
+            "down" => OperState::Down,
+            "lowerlayerdown" => OperState::LowerLayerDown,
+            "testing" => OperState::Testing,
+            "dormant" => OperState::Dormant,
+            "up" => OperState::Up,
+            other => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("unrecognized operstate {:?}", other)))
+            }
+        };
+        Ok(state)
+    }
+
+    /// Blocks until the kernel announces a change to this interface over
+    /// netlink (`RTM_NEWLINK`) and returns its carrier state at that point.
+    ///
+    /// Returns an error of kind `TimedOut` if no change is announced within
+    /// `timeout`, and `NotFound` if the interface is removed (`RTM_DELLINK`).
+    pub fn wait_carrier_change(&self, timeout: Duration) -> io::Result<bool> {
+        let index = self.ifindex()?;
+
+        let sock = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE)
+        };
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let res = recv_link_change(sock, index as i32, timeout);
+        let _ = close(sock);
+        res
+    }
+
+    /// Writes the Ethernet frame `frame` to a TAP device, returning its
+    /// length.
+    ///
+    /// The frame starts with the 14-byte Ethernet header and must not carry a
+    /// trailing FCS, which the kernel neither expects nor checks; use
+    /// `send_ethernet_with_fcs` for frames that do. Returns an `Unsupported`
+    /// error on a TUN device.
+    pub fn send_ethernet(&self, frame: &[u8]) -> io::Result<usize> {
+        if !self.tap {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "not a TAP device"));
+        }
+        if frame.len() < 14 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame shorter than an Ethernet header"));
+        }
+        if self.discard_writes {
+            return Ok(frame.len());
+        }
+
+        // The packet information protocol is the frame's EtherType.
+        self.write_framed(&mut Vec::new(), [0, 0, frame[12], frame[13]], frame)
+    }
+
+    /// Writes the Ethernet frame `frame` like `send_ethernet`, first dropping
+    /// the 4-byte FCS it ends with, and returns the length written without it.
+    ///
+    /// The FCS isn't checked, so frames taken off the wire with one can be
+    /// passed through as they are.
+    pub fn send_ethernet_with_fcs(&self, frame: &[u8]) -> io::Result<usize> {
+        if frame.len() < 18 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame shorter than an Ethernet header and FCS"));
+        }
+        self.send_ethernet(&frame[..frame.len() - 4])
+    }
+
+    /// Reads one Ethernet frame from a TAP device into `buf`, without the
+    /// packet information header, and returns its length.
+    ///
+    /// `buf` needs room for the header on top of the frame. Returns an
+    /// `Unsupported` error on a TUN device.
+    pub fn recv_ethernet(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.tap {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "not a TAP device"));
+        }
+
+        let mut stream = self;
+        let len = stream.read(buf)?;
+        let header_len = self.header_len();
+        if len < header_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame shorter than its header"));
+        }
+
+        buf.copy_within(header_len..len, 0);
+        Ok(len - header_len)
+    }
+
+    /// Writes a packet with the header for `version`, regardless of what its
+    /// first byte says, and returns the payload length.
+    ///
+    /// Without packet info the kernel sniffs the protocol from the packet
+    /// itself, so `version` has no effect.
+    pub fn write_as(&self, version: IpVersion, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.discard_writes {
+            return Ok(buf.len());
+        }
+
+        self.write_framed(&mut Vec::new(), header_for(version), buf)
+    }
+
+    /// Writes a packet like `write`, building the header and packet in
+    /// `scratch` rather than a fresh allocation.
+    ///
+    /// `scratch` is cleared first, so keeping one around for the life of a
+    /// write loop saves an allocation per packet.
+    pub fn write_into(&self, scratch: &mut Vec<u8>, buf: &[u8]) -> io::Result<usize> {
+        let version = match buf.first().map(|b| b >> 4) {
+            None => return Ok(0),
+            Some(_) if self.discard_writes => return Ok(buf.len()),
+            Some(4) => IpVersion::V4,
+            Some(6) => IpVersion::V6,
+            Some(_) => match self.unknown_policy {
+                UnknownPacketPolicy::Drop => return Ok(0),
+                UnknownPacketPolicy::Error => return Err(io::Error::other("unrecognized IP version")),
+                UnknownPacketPolicy::Assume(version) => version,
+            },
+        };
+        self.write_framed(scratch, header_for(version), buf)
+    }
+
+    /// Writes `buf` behind whichever headers the device was opened with,
+    /// using `pi` as the packet information header, and returns the payload
+    /// length. The headers and packet are put together in `scratch`.
+    fn write_framed(&self, scratch: &mut Vec<u8>, pi: [u8; HEADER_LEN], buf: &[u8]) -> io::Result<usize> {
+        scratch.clear();
+        if self.packet_info {
+            scratch.extend_from_slice(&pi);
+        }
+        // With IFF_NO_PI the kernel sniffs the protocol from the packet itself.
+        if self.vnet_hdr {
+            // VIRTIO_NET_HDR_GSO_NONE, without checksum offload.
+            scratch.extend_from_slice(&[0u8; VNET_HDR_LEN]);
+        }
+
+        let header_len = scratch.len();
+        let res = if header_len == 0 {
+            write(self.fd, buf)
+        } else {
+            scratch.extend_from_slice(buf);
+            write(self.fd, scratch).map(|len| len.saturating_sub(header_len))
+        };
+
+        let len = res.map_err(io_error)?;
+
+        self.counters.count_tx();
+        Ok(len)
+    }
+
+    /// Reads one packet like `read`, and returns it along with the time it
+    /// was received.
+    ///
+    /// The tun character device offers no kernel receive timestamps, so the
+    /// time is taken right after the read returns and includes any delay
+    /// before the packet was read.
+    pub fn recv_timestamped(&self, buf: &mut [u8]) -> io::Result<(usize, SystemTime)> {
+        let mut stream = self;
+        let len = stream.read(buf)?;
+        Ok((len, SystemTime::now()))
+    }
+
+    /// Returns the number of bytes written but not yet taken by the network
+    /// stack, mirroring the macOS API.
+    ///
+    /// A tun device has no send queue, as `drain_tx` explains, so this is
+    /// always zero: back off on `WouldBlock` instead.
+    pub fn send_queue_len(&self) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    /// Waits for written packets to leave the device's send queue, mirroring
+    /// the macOS API.
+    ///
+    /// A write to a tun device hands the packet to the network stack before
+    /// it returns, so there's never anything left to wait for and this
+    /// returns immediately.
+    pub fn drain_tx(&self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Takes the pending error on the device, mirroring the macOS API.
+    ///
+    /// The tun character device reports errors from each call directly and
+    /// never has one pending, so this always returns `Ok(None)`.
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(None)
+    }
+
+    /// Reads one packet into `buf`, without its header, and reports what was
+    /// read.
+    ///
+    /// With packet info the kernel flags truncated packets (`TUN_PKT_STRIP`).
+    /// Without it there's no way to tell, so a packet that exactly fills
+    /// `buf` is reported as truncated.
+    pub fn recv_detailed(&self, buf: &mut [u8]) -> io::Result<RecvInfo> {
+        let header_len = self.header_len();
+        let mut header = [0u8; HEADER_LEN + VNET_HDR_LEN];
+        let len = readv(self.fd, &mut [IoVec::from_mut_slice(&mut header[..header_len]),
+                                       IoVec::from_mut_slice(buf)])
+            .map_err(io_error)?;
+        if len < header_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "packet shorter than its header"));
+        }
+        self.counters.count_rx();
+
+        let payload_len = len - header_len;
+        let truncated = if self.packet_info {
+            NativeEndian::read_u16(&header[..2]) & TUN_PKT_STRIP != 0
+        } else {
+            payload_len == buf.len()
+        };
+
+        Ok(RecvInfo {
+            payload_len,
+            header_len,
+            truncated,
+            version: IpVersion::of(&buf[..payload_len]),
+        })
+    }
+
+    /// Sets whether `write` discards packets instead of sending them, which
+    /// lets benchmarks measure the read side without the write syscall.
+    ///
+    /// Discarded packets are reported as written in full but aren't counted
+    /// in `stats`.
+    pub fn set_discard_writes(&mut self, discard: bool) {
+        self.discard_writes = discard;
+    }
+
+    /// Sets the source and destination of the empty UDP datagrams that
+    /// `send_keepalive` writes.
+    pub fn set_keepalive(&mut self, src: SocketAddr, dst: SocketAddr) {
+        self.keepalive = Some((src, dst));
+    }
+
+    /// Returns the keepalive endpoints set with `set_keepalive`.
+    pub(crate) fn keepalive(&self) -> Option<(SocketAddr, SocketAddr)> {
+        self.keepalive
+    }
+
+    /// Sets what `write` does with packets that aren't IPv4 or IPv6 by their
+    /// first nibble. Defaults to `UnknownPacketPolicy::Error`.
+    pub fn set_unknown_policy(&mut self, policy: UnknownPacketPolicy) {
+        self.unknown_policy = policy;
+    }
+
+    /// Sets whether `write` rejects packets that aren't IPv4 or IPv6 by their
+    /// first nibble. Defaults to `true`.
+    ///
+    /// When disabled, such packets are written with the IPv4 header, which
+    /// suits forwarders whose packets were already validated upstream. This
+    /// is shorthand for `set_unknown_policy` with `Error` or `Assume(V4)`.
+    pub fn set_strict_version_check(&mut self, strict: bool) {
+        self.set_unknown_policy(if strict {
+            UnknownPacketPolicy::Error
+        } else {
+            UnknownPacketPolicy::Assume(IpVersion::V4)
+        });
+    }
+
+    pub(crate) fn packet_info(&self) -> bool {
+        self.packet_info
+    }
+
+    pub(crate) fn set_packet_info(&mut self, enabled: bool) {
+        self.packet_info = enabled;
+    }
+
+    /// Returns the length of the header that precedes each packet read from
+    /// this stream.
+    pub(crate) fn header_len(&self) -> usize {
+        let pi = if self.packet_info { HEADER_LEN } else { 0 };
+        let vnet = if self.vnet_hdr { VNET_HDR_LEN } else { 0 };
+        pi + vnet
+    }
+
+    /// Returns the length of the headers the kernel puts in front of each
+    /// packet, which are the ones `read` leaves in place.
+    pub(crate) fn kernel_header_len(&self) -> usize {
+        self.header_len()
+    }
+
+    /// Attaches the eBPF program `prog_fd` to steer packets among the queues
+    /// of a multiqueue device (`TUNSETSTEERINGEBPF`), replacing the kernel's
+    /// flow hash. The program returns the index of the queue to use.
+    ///
+    /// Pass `-1` to detach the program. Requires `CAP_NET_ADMIN`.
+    pub fn set_steering_program(&self, prog_fd: RawFd) -> io::Result<()> {
+        let mut fd = prog_fd;
+        match unsafe { tunsetsteeringebpf(self.fd, &mut fd) } {
+            Ok(_) => Ok(()),
+            Err(nix::Error::Sys(errno)) => Err(io::Error::from_raw_os_error(errno as i32)),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    /// Returns the number of packets read from and written to this stream.
+    pub fn stats(&self) -> QueueStat {
+        self.counters.get()
+    }
+
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub(crate) fn discards_writes(&self) -> bool {
+        self.discard_writes
+    }
+
+    pub(crate) fn pool(&self) -> Option<&BufferPool> {
+        self.pool.as_ref()
+    }
+
     /// Shuts down the read, write, or both halves of this connection.
     ///
     /// This function will cause all pending and future I/O on the specified
@@ -96,6 +783,219 @@ impl UtunStream {
     }
 }
 
+impl UtunBuilder {
+    /// Creates a builder for the interface `name` with the default options.
+    ///
+    /// Any valid interface name is accepted as-is, so the macOS-style `utunN`
+    /// names work too and open an interface with exactly that name.
+    pub fn new(name: &str) -> Self {
+        UtunBuilder {
+            name: name.to_string(),
+            packet_info: true,
+            tap: false,
+            vnet_hdr: false,
+            nonblocking: true,
+            device_path: PathBuf::from(DEFAULT_DEVICE_PATH),
+            buffer_pool: None,
+        }
+    }
+
+    /// Sets whether packets read from the device are prefixed with the 4-byte
+    /// packet information header (flags and protocol).
+    ///
+    /// Disabling this sets `IFF_NO_PI`, so reads return the bare IP packet.
+    /// Writes always take a bare IP packet. Defaults to `true`.
+    pub fn packet_info(&mut self, enabled: bool) -> &mut Self {
+        self.packet_info = enabled;
+        self
+    }
+
+    /// Sets whether to open a TAP device, which carries Ethernet frames,
+    /// instead of a TUN device. Defaults to `false`.
+    ///
+    /// Frames are sent and received with `UtunStream::send_ethernet` and
+    /// `UtunStream::recv_ethernet`; the `Read` and `Write` impls expect IP
+    /// packets.
+    pub fn tap(&mut self, tap: bool) -> &mut Self {
+        self.tap = tap;
+        self
+    }
+
+    /// Sets whether to open the device with `IFF_VNET_HDR`, which puts a
+    /// `virtio_net_hdr` between the packet information header and each
+    /// packet. Defaults to `false`.
+    ///
+    /// The offload ioctls have no effect without it. Reads include the header
+    /// (see `DecapReader` to strip it), and writes prepend a zeroed one that
+    /// requests neither segmentation nor checksum offload.
+    pub fn vnet_hdr(&mut self, enabled: bool) -> &mut Self {
+        self.vnet_hdr = enabled;
+        self
+    }
+
+    /// Sets whether the device is opened in non-blocking mode, as required
+    /// for use with mio. Defaults to `true`.
+    pub fn nonblocking(&mut self, nonblocking: bool) -> &mut Self {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Sets the path of the tun clone device to open, for environments where
+    /// it's mounted somewhere other than the default: `/dev/tun` on Android
+    /// and `/dev/net/tun` elsewhere.
+    pub fn device_path(&mut self, path: &Path) -> &mut Self {
+        self.device_path = path.to_path_buf();
+        self
+    }
+
+    /// Gives the stream a pool of `capacity` buffers of `buf_size` bytes,
+    /// which `UtunStream::recv` reads packets into.
+    ///
+    /// `buf_size` should leave room for the packet header on top of the MTU.
+    pub fn buffer_pool(&mut self, capacity: usize, buf_size: usize) -> &mut Self {
+        self.buffer_pool = Some((capacity, buf_size));
+        self
+    }
+
+    /// Opens the tun device with the configured options.
+    ///
+    /// This needs `CAP_NET_ADMIN`, which Android apps don't have; there the
+    /// device comes from `VpnService.Builder.establish()` as an fd, to be
+    /// adopted with `UtunStream::from_owned_fd` or `from_raw_fd` instead.
+    pub fn connect(&self) -> io::Result<UtunStream> {
+        let name = &self.name;
+        validate_name(name)?;
+
+        let mut oflag = OFlag::O_RDWR;
+        if self.nonblocking {
+            oflag |= OFlag::O_NONBLOCK;
+        }
+
+        let fd = open(self.device_path.as_path(), oflag, Mode::empty())
+            .map_err(io::Error::other)?;
+
+        let mut flags = if self.tap { IFF_TAP } else { IFF_TUN };
+        if !self.packet_info {
+            flags |= IFF_NO_PI;
+        }
+        if self.vnet_hdr {
+            flags |= IFF_VNET_HDR;
+        }
+
+        let mut req = ifreq::new(name);
+        req.ifr_ifru.ifru_flags = flags;
+
+        unsafe { tunsetiff(fd, &mut req as *mut ifreq as *mut _) }
+            .map_err(io::Error::other)?;
+
+        Ok(UtunStream {
+            fd,
+            packet_info: self.packet_info,
+            tap: self.tap,
+            vnet_hdr: self.vnet_hdr,
+            counters: Counters::default(),
+            pool: self.buffer_pool.map(|(capacity, buf_size)| BufferPool::new(capacity, buf_size)),
+            discard_writes: false,
+            unknown_policy: UnknownPacketPolicy::Error,
+            keepalive: None,
+            last_ifreq: req.to_bytes(),
+        })
+    }
+}
+
+/// Returns the flags the tun device `name` was created with, as reported by
+/// `/sys/class/net/<name>/tun_flags`.
+///
+/// `TUNGETIFF` can't be trusted for this: it sets `IFF_NOFILTER`, which has
+/// the same value as `IFF_NO_PI`, whenever no socket filter is attached.
+fn sysfs_tun_flags(name: &str) -> Option<i16> {
+    let flags = fs::read_to_string(format!("/sys/class/net/{}/tun_flags", name)).ok()?;
+    u16::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok().map(|f| f as i16)
+}
+
+/// Returns the flags that can be trusted among those `TUNGETIFF` reported.
+///
+/// Its `IFF_NOFILTER` bit, set whenever no socket filter is attached, has the
+/// same value as `IFF_NO_PI`, so the ioctl can't tell whether the device
+/// carries packet info. The bit is taken at its word on Android, whose
+/// `VpnService` opens the device with `IFF_NO_PI`; elsewhere, packet info is
+/// the default.
+fn ioctl_tun_flags(flags: i16) -> i16 {
+    if cfg!(target_os = "android") {
+        flags
+    } else {
+        flags & !IFF_NO_PI
+    }
+}
+
+/// Returns `addr` as a `sockaddr_in`, cast to the `sockaddr` that `ifreq`
+/// carries addresses as.
+fn sockaddr_in(addr: Ipv4Addr) -> libc::sockaddr {
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(addr.octets()) },
+        sin_zero: [0; 8],
+    };
+    unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(sin) }
+}
+
+/// Returns the address of the `sockaddr_in` that `addr` holds.
+fn ipv4_of(addr: libc::sockaddr) -> Ipv4Addr {
+    let sin = unsafe { mem::transmute::<libc::sockaddr, libc::sockaddr_in>(addr) };
+    Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes())
+}
+
+/// Subscribes the netlink socket `sock` to link notifications and waits for
+/// one about the interface `index`, returning whether it has carrier.
+fn recv_link_change(sock: RawFd, index: i32, timeout: Duration) -> io::Result<bool> {
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = libc::RTMGRP_LINK as u32;
+    let res = unsafe {
+        libc::bind(sock,
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 8192];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [PollFd::new(sock, EventFlags::POLLIN)];
+        if poll(&mut fds, remaining.as_millis() as libc::c_int).map_err(io::Error::other)? == 0 {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        let len = read(sock, &mut buf).map_err(io::Error::other)?;
+
+        // Walk the nlmsghdr (16 bytes) + ifinfomsg (16 bytes) messages.
+        let mut off = 0;
+        while off + 32 <= len {
+            let msg_len = NativeEndian::read_u32(&buf[off..]) as usize;
+            let msg_type = NativeEndian::read_u16(&buf[off + 4..]);
+            let msg_index = NativeEndian::read_i32(&buf[off + 20..]);
+            let msg_flags = NativeEndian::read_u32(&buf[off + 24..]);
+
+            if msg_index == index {
+                match msg_type {
+                    libc::RTM_NEWLINK => return Ok(msg_flags & libc::IFF_LOWER_UP as u32 != 0),
+                    libc::RTM_DELLINK => return Err(io::ErrorKind::NotFound.into()),
+                    _ => {}
+                }
+            }
+
+            if msg_len == 0 {
+                break;
+            }
+            off += (msg_len + 3) & !3;
+        }
+    }
+}
+
 impl Drop for UtunStream {
     fn drop(&mut self) {
         // Ignore error...
@@ -105,39 +1005,22 @@ impl Drop for UtunStream {
 
 impl Read for UtunStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        read(self.fd, buf)
-        .map_err(|e| match e {
-            nix::Error::Sys(Errno::EAGAIN) => io::ErrorKind::WouldBlock.into(),
-            _ => io::Error::new(io::ErrorKind::Other, e)
-        })
+        (&*self).read(buf)
     }
 }
 
-impl<'a> Read for &'a UtunStream {
+impl Read for &UtunStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        read(self.fd, buf)
-        .map_err(|e| match e {
-            nix::Error::Sys(Errno::EAGAIN) => io::ErrorKind::WouldBlock.into(),
-            _ => io::Error::new(io::ErrorKind::Other, e)
-        })
+        let len = read(self.fd, buf).map_err(io_error)?;
+
+        self.counters.count_rx();
+        Ok(len)
     }
 }
 
 impl Write for UtunStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if buf.len() == 0 {
-            return Ok(0);
-        }
-
-        match buf[0] >> 4 {
-            4 => write(self.fd, &[&[0u8, 0x00, 0x08, 0x00], buf].concat()),
-            6 => write(self.fd, &[&[0u8, 0x00, 0x86, 0xdd], buf].concat()),
-            _ => return Err(io::Error::new(io::ErrorKind::Other, "unrecognized IP version")),
-        }.map(|len| len - 4)
-        .map_err(|e| match e {
-            nix::Error::Sys(Errno::EAGAIN) => io::ErrorKind::WouldBlock.into(),
-            _ => io::Error::new(io::ErrorKind::Other, e)
-        })
+        (&*self).write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -145,21 +1028,9 @@ impl Write for UtunStream {
     }
 }
 
-impl<'a> Write for &'a UtunStream {
+impl Write for &UtunStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if buf.len() == 0 {
-            return Ok(0);
-        }
-
-        match buf[0] >> 4 {
-            4 => write(self.fd, &[&[0u8, 0x00, 0x08, 0x00], buf].concat()),
-            6 => write(self.fd, &[&[0u8, 0x00, 0x86, 0xdd], buf].concat()),
-            _ => return Err(io::Error::new(io::ErrorKind::Other, "unrecognized IP version")),
-        }.map(|len| len - 4)
-        .map_err(|e| match e {
-            nix::Error::Sys(Errno::EAGAIN) => io::ErrorKind::WouldBlock.into(),
-            _ => io::Error::new(io::ErrorKind::Other, e)
-        })
+        self.write_into(&mut Vec::new(), buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -198,7 +1069,35 @@ impl IntoRawFd for UtunStream {
 }
 
 impl FromRawFd for UtunStream {
+    /// Adopts `fd`, taking the framing from the flags the device was opened
+    /// with, such as `IFF_NO_PI` on the fds Android's `VpnService` hands out.
+    ///
+    /// The flags are read from `/sys/class/net/<name>/tun_flags`. Without
+    /// sysfs, whether the device carries packet info can't be told reliably,
+    /// and it's assumed to, except on Android. An fd that isn't a tun device
+    /// is assumed to carry packet info.
     unsafe fn from_raw_fd(fd: RawFd) -> Self {
-        Self { fd }
+        let mut req = ifreq::new("");
+        let flags = match tungetiff(fd, &mut req as *mut ifreq as *mut u32) {
+            Ok(_) => {
+                let len = req.ifr_name.iter().position(|&b| b == 0).unwrap_or(IFNAMSIZ);
+                let name = String::from_utf8_lossy(&req.ifr_name[..len]);
+                sysfs_tun_flags(&name).unwrap_or_else(|| ioctl_tun_flags(req.ifr_ifru.ifru_flags))
+            }
+            Err(_) => IFF_TUN,
+        };
+
+        Self {
+            fd,
+            packet_info: flags & IFF_NO_PI == 0,
+            tap: flags & IFF_TAP != 0,
+            vnet_hdr: flags & IFF_VNET_HDR != 0,
+            counters: Counters::default(),
+            pool: None,
+            discard_writes: false,
+            unknown_policy: UnknownPacketPolicy::Error,
+            keepalive: None,
+            last_ifreq: req.to_bytes(),
+        }
     }
 }
@@ -2,7 +2,6 @@
 
 #![cfg(unix)]
 #![deny(missing_docs)]
-#![doc(html_root_url = "https://docs.rs/mio-utun/0.6")]
 
 extern crate mio;
 extern crate nix;
@@ -12,21 +11,115 @@ use mio::unix::EventedFd;
 use mio::event::Evented;
 use mio::{Poll, Token, Ready, PollOpt};
 
-use nix::errno::Errno;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::unistd::{close, read, write};
+use nix::sys::uio::{readv, IoVec};
 use nix::sys::socket::{AddressFamily, SockAddr, SockType, SockFlag, SockProtocol, Shutdown, socket, connect, shutdown};
+use nix::sys::socket::{sockopt, getsockopt, setsockopt, recvmsg, CmsgSpace, ControlMessage, MsgFlags};
+use nix::sys::time::{TimeVal, TimeValLike};
 
+use std::ffi::CString;
 use std::mem;
+use framing::{self, HeaderFormat};
+use {io_error, validate_name, Counters, IpVersion, QueueStat, RecvInfo, UnknownPacketPolicy};
+use pool::BufferPool;
 use std::io::{self, Read, Write};
+use std::net::SocketAddr;
 use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// The primary class for this crate, a stream of tunneled traffic.
 #[derive(Debug)]
 pub struct UtunStream {
     fd: RawFd,
+    packet_info: bool,
+    counters: Counters,
+    pool: Option<BufferPool>,
+    discard_writes: bool,
+    unknown_policy: UnknownPacketPolicy,
+    keepalive: Option<(SocketAddr, SocketAddr)>,
+    timestamps_enabled: AtomicBool,
 }
 
+/// Returns the control unit to connect to for the interface `utun<ifnum>`.
+///
+/// Unit 0 is reserved for asking the kernel for the first free interface, so
+/// the units of actual interfaces are offset by one. Returns `None` for
+/// `u32::MAX`, which has no unit.
+pub fn utun_unit_for(ifnum: u32) -> Option<u32> {
+    ifnum.checked_add(1)
+}
+
+/// Returns the number of the `utunN` interface that the control unit `unit`
+/// creates: the inverse of `utun_unit_for`.
+///
+/// Returns `None` for unit 0, which doesn't name a particular interface.
+pub fn ifnum_for_unit(unit: u32) -> Option<u32> {
+    unit.checked_sub(1)
+}
+
+/// Options which can be used to configure how a utun device is opened.
+///
+/// `UtunStream::connect` is equivalent to `UtunBuilder::new(name).connect()`.
+#[derive(Debug, Clone)]
+pub struct UtunBuilder {
+    name: String,
+    packet_info: bool,
+    nonblocking: bool,
+    buffer_pool: Option<(usize, usize)>,
+}
+
+const IFF_RUNNING: i16 = libc::IFF_RUNNING as i16;
+const IFF_NOARP: i16 = libc::IFF_NOARP as i16;
+
+/// The length of the address family header prepended to each packet.
+pub const HEADER_LEN: usize = framing::HEADER_LEN;
+
+/// Returns the header written ahead of a packet of the given version: its
+/// address family as a big-endian `u32`.
+pub fn header_for(version: IpVersion) -> [u8; HEADER_LEN] {
+    framing::header_for(HeaderFormat::AddressFamily, version)
+}
+
+/// Returns whether a network interface called `name` exists, by looking up
+/// its index with `if_nametoindex`.
+///
+/// Invalid names, as `validate_name` judges them, never exist.
+pub fn interface_exists(name: &str) -> bool {
+    if validate_name(name).is_err() {
+        return false;
+    }
+    let name = CString::new(name).expect("validated names have no NUL");
+    unsafe { libc::if_nametoindex(name.as_ptr()) != 0 }
+}
+
+/// Returns the kqueue filters to watch a tunnel fd for reads and writes with,
+/// as `(EVFILT_READ, EVFILT_WRITE)`, for event loops that don't go through
+/// mio.
+///
+/// The utun fd is a socket, so the plain socket filters apply, and both
+/// support `EV_CLEAR`. mio registers with `EV_CLEAR` for edge-triggered
+/// polling; if you do too, read (or write) until `WouldBlock` before waiting
+/// again, or later packets can sit unnoticed in the queue.
+pub fn kqueue_filters() -> (i16, i16) {
+    (libc::EVFILT_READ, libc::EVFILT_WRITE)
+}
+
+ioctl!(
+    /// `SIOCGIFFLAGS`, which reads the flags of an interface into an `ifreq`.
+    readwrite siocgifflags with b'i', 17; [u8; 32]
+);
+ioctl!(
+    /// `SIOCSIFFLAGS`, which sets the flags of an interface from an `ifreq`.
+    write_ptr siocsifflags with b'i', 16; [u8; 32]
+);
+ioctl!(
+    /// `SIOCGIFMTU`, which reads the MTU of an interface into an `ifreq`.
+    readwrite siocgifmtu with b'i', 51; [u8; 32]
+);
+
 impl UtunStream {
     /// Create a new TCP stream and issue a non-blocking connect to the
     /// specified address.
@@ -38,35 +131,7 @@ impl UtunStream {
     /// `TcpStream::connect_stream` to transfer ownership into mio and schedule
     /// the connect operation.
     pub fn connect(name: &str) -> io::Result<Self> {
-        if &name[..4] != "utun" {
-            return Err(io::ErrorKind::AddrNotAvailable.into());
-        }
-
-        let unit: u32 = if name.len() == 4 {
-            0
-        } else {
-            1 + name[4..].parse::<u32>().map_err(|_| io::Error::from(io::ErrorKind::Other))?
-        };
-
-        let fd: RawFd = socket(AddressFamily::System,
-                               SockType::Datagram,
-                               SockFlag::empty(),
-                               SockProtocol::KextControl)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-        let addr = SockAddr::new_sys_control(fd,
-                                             "com.apple.net.utun_control",
-                                             unit)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-        fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-        connect(fd, &addr)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-
-        return Ok(UtunStream { fd })
+        UtunBuilder::new(name).connect()
     }
 
     /// Returns the name of the interface as assigned by the OS.
@@ -89,6 +154,311 @@ impl UtunStream {
         }
     }
 
+    /// Returns the interface flags (`IFF_UP`, `IFF_RUNNING`, ...) as reported
+    /// by `SIOCGIFFLAGS`.
+    pub fn flags(&self) -> io::Result<i16> {
+        let mut req = [0u8; 32];
+        self.if_ioctl(&mut req, siocgifflags)?;
+        Ok(i16::from_ne_bytes([req[16], req[17]]))
+    }
+
+    /// Replaces the interface flags with `flags` (`SIOCSIFFLAGS`).
+    fn set_flags(&self, flags: i16) -> io::Result<()> {
+        let mut req = [0u8; 32];
+        req[16..18].copy_from_slice(&flags.to_ne_bytes());
+        self.if_ioctl(&mut req, |sock, req| unsafe { siocsifflags(sock, req) })
+    }
+
+    /// Returns whether ARP is enabled on the interface, i.e. whether
+    /// `IFF_NOARP` is clear.
+    pub fn arp(&self) -> io::Result<bool> {
+        Ok(self.flags()? & IFF_NOARP == 0)
+    }
+
+    /// Enables or disables ARP on the interface by clearing or setting
+    /// `IFF_NOARP`, as `ip link set arp on|off` does.
+    ///
+    /// A point-to-point tunnel has no use for ARP. Needs the privileges to
+    /// configure the interface.
+    pub fn set_arp(&self, enabled: bool) -> io::Result<()> {
+        let flags = self.flags()?;
+        self.set_flags(if enabled { flags & !IFF_NOARP } else { flags | IFF_NOARP })
+    }
+
+    /// Returns the MTU of the interface.
+    pub fn mtu(&self) -> io::Result<u32> {
+        let mut req = [0u8; 32];
+        self.if_ioctl(&mut req, siocgifmtu)?;
+        Ok(i32::from_ne_bytes([req[16], req[17], req[18], req[19]]) as u32)
+    }
+
+    /// Fills in this interface's name in `req` and issues the interface ioctl
+    /// `f` with it on a throwaway socket.
+    fn if_ioctl(&self, req: &mut [u8; 32],
+                f: unsafe fn(libc::c_int, *mut [u8; 32]) -> nix::Result<libc::c_int>)
+                -> io::Result<()> {
+        let name = self.name()?;
+        req[..name.len()].copy_from_slice(name.as_bytes());
+
+        let sock = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::empty(), None)
+            .map_err(io::Error::other)?;
+        let res = unsafe { f(sock, req) };
+        let _ = close(sock);
+
+        res.map(|_| ()).map_err(io::Error::other)
+    }
+
+    /// Blocks until the kernel reports the interface as `IFF_RUNNING`.
+    ///
+    /// Returns an error of kind `TimedOut` if the flag is still unset after
+    /// `timeout`.
+    pub fn wait_until_running(&self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.flags()? & IFF_RUNNING != 0 {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Writes a packet with the header for `version`, regardless of what its
+    /// first byte says, and returns the payload length.
+    pub fn write_as(&self, version: IpVersion, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.discard_writes {
+            return Ok(buf.len());
+        }
+
+        self.write_framed(&mut Vec::new(), header_for(version), buf)
+    }
+
+    /// Writes a packet like `write`, building the header and packet in
+    /// `scratch` rather than a fresh allocation.
+    ///
+    /// `scratch` is cleared first, so keeping one around for the life of a
+    /// write loop saves an allocation per packet.
+    pub fn write_into(&self, scratch: &mut Vec<u8>, buf: &[u8]) -> io::Result<usize> {
+        let version = match buf.first().map(|b| b >> 4) {
+            None => return Ok(0),
+            Some(_) if self.discard_writes => return Ok(buf.len()),
+            Some(4) => IpVersion::V4,
+            Some(6) => IpVersion::V6,
+            Some(_) => match self.unknown_policy {
+                UnknownPacketPolicy::Drop => return Ok(0),
+                UnknownPacketPolicy::Error => return Err(io::Error::other("unrecognized IP version")),
+                UnknownPacketPolicy::Assume(version) => version,
+            },
+        };
+        self.write_framed(scratch, header_for(version), buf)
+    }
+
+    /// Writes `buf` behind `header`, putting the two together in `scratch`,
+    /// and returns the payload length.
+    fn write_framed(&self, scratch: &mut Vec<u8>, header: [u8; HEADER_LEN], buf: &[u8]) -> io::Result<usize> {
+        scratch.clear();
+        scratch.extend_from_slice(&header);
+        scratch.extend_from_slice(buf);
+
+        let len = write(self.fd, scratch)
+        .map(|len| len - HEADER_LEN)
+        .map_err(io_error)?;
+
+        self.counters.count_tx();
+        Ok(len)
+    }
+
+    /// Reads one packet like `read`, and returns it along with the time the
+    /// kernel received it (`SO_TIMESTAMP`).
+    ///
+    /// Receive timestamps are enabled on the first call, so packets queued
+    /// before then fall back to the time the read returned.
+    pub fn recv_timestamped(&self, buf: &mut [u8]) -> io::Result<(usize, SystemTime)> {
+        if !self.timestamps_enabled.load(Ordering::Relaxed) {
+            setsockopt(self.fd, sockopt::ReceiveTimestamp, &true)
+                .map_err(io::Error::other)?;
+            self.timestamps_enabled.store(true, Ordering::Relaxed);
+        }
+
+        let mut header = [0u8; HEADER_LEN];
+        let mut iov = Vec::with_capacity(2);
+        if !self.packet_info {
+            iov.push(IoVec::from_mut_slice(&mut header));
+        }
+        iov.push(IoVec::from_mut_slice(buf));
+
+        let mut space: CmsgSpace<TimeVal> = CmsgSpace::new();
+        let msg = recvmsg(self.fd, &iov, Some(&mut space), MsgFlags::empty())
+            .map_err(io_error)?;
+        let header_len = (iov.len() - 1) * HEADER_LEN;
+        if msg.bytes < header_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "packet shorter than its header"));
+        }
+
+        let mut time = SystemTime::now();
+        for cmsg in msg.cmsgs() {
+            if let ControlMessage::ScmTimestamp(tv) = cmsg {
+                time = UNIX_EPOCH + Duration::from_micros(tv.num_microseconds() as u64);
+            }
+        }
+
+        self.counters.count_rx();
+        Ok((msg.bytes - header_len, time))
+    }
+
+    /// Reads one packet into `buf`, without its header, and reports what was
+    /// read, including whether the socket truncated it (`MSG_TRUNC`).
+    pub fn recv_detailed(&self, buf: &mut [u8]) -> io::Result<RecvInfo> {
+        let mut header = [0u8; HEADER_LEN];
+        let msg = recvmsg::<()>(self.fd,
+                                &[IoVec::from_mut_slice(&mut header), IoVec::from_mut_slice(buf)],
+                                None,
+                                MsgFlags::empty())
+            .map_err(io_error)?;
+        if msg.bytes < HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "packet shorter than its header"));
+        }
+        self.counters.count_rx();
+
+        let payload_len = msg.bytes - HEADER_LEN;
+        Ok(RecvInfo {
+            payload_len,
+            header_len: HEADER_LEN,
+            truncated: msg.flags.contains(MsgFlags::MSG_TRUNC),
+            version: IpVersion::of(&buf[..payload_len]),
+        })
+    }
+
+    /// Returns the number of bytes queued in the control socket's send
+    /// buffer (`SO_NWRITE`), waiting to be taken by the kernel.
+    pub fn send_queue_len(&self) -> io::Result<usize> {
+        let mut queued: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(self.fd,
+                libc::SOL_SOCKET,
+                libc::SO_NWRITE,
+                &mut queued as *mut libc::c_int as *mut libc::c_void,
+                &mut len)
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(queued as usize)
+    }
+
+    /// Blocks until the control socket's send buffer has drained
+    /// (`SO_NWRITE` reads zero), such as before tearing the interface down.
+    ///
+    /// Returns an error of kind `TimedOut` if bytes are still queued after
+    /// `timeout`.
+    pub fn drain_tx(&self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let queued = self.send_queue_len()?;
+            if queued == 0 {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Takes the pending error on the control socket (`SO_ERROR`), clearing
+    /// it, in the manner of `TcpStream::take_error`.
+    ///
+    /// This is the underlying failure after a poll reports an error.
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        match getsockopt(self.fd, sockopt::SocketError).map_err(io::Error::other)? {
+            0 => Ok(None),
+            errno => Ok(Some(io::Error::from_raw_os_error(errno))),
+        }
+    }
+
+    /// Sets whether `write` discards packets instead of sending them, which
+    /// lets benchmarks measure the read side without the write syscall.
+    ///
+    /// Discarded packets are reported as written in full but aren't counted
+    /// in `stats`.
+    pub fn set_discard_writes(&mut self, discard: bool) {
+        self.discard_writes = discard;
+    }
+
+    /// Sets the source and destination of the empty UDP datagrams that
+    /// `send_keepalive` writes.
+    pub fn set_keepalive(&mut self, src: SocketAddr, dst: SocketAddr) {
+        self.keepalive = Some((src, dst));
+    }
+
+    /// Returns the keepalive endpoints set with `set_keepalive`.
+    pub(crate) fn keepalive(&self) -> Option<(SocketAddr, SocketAddr)> {
+        self.keepalive
+    }
+
+    /// Sets what `write` does with packets that aren't IPv4 or IPv6 by their
+    /// first nibble. Defaults to `UnknownPacketPolicy::Error`.
+    pub fn set_unknown_policy(&mut self, policy: UnknownPacketPolicy) {
+        self.unknown_policy = policy;
+    }
+
+    /// Sets whether `write` rejects packets that aren't IPv4 or IPv6 by their
+    /// first nibble. Defaults to `true`.
+    ///
+    /// When disabled, such packets are written with the IPv4 header, which
+    /// suits forwarders whose packets were already validated upstream. This
+    /// is shorthand for `set_unknown_policy` with `Error` or `Assume(V4)`.
+    pub fn set_strict_version_check(&mut self, strict: bool) {
+        self.set_unknown_policy(if strict {
+            UnknownPacketPolicy::Error
+        } else {
+            UnknownPacketPolicy::Assume(IpVersion::V4)
+        });
+    }
+
+    pub(crate) fn packet_info(&self) -> bool {
+        self.packet_info
+    }
+
+    pub(crate) fn set_packet_info(&mut self, enabled: bool) {
+        self.packet_info = enabled;
+    }
+
+    /// Returns the length of the header that precedes each packet read from
+    /// this stream.
+    pub(crate) fn header_len(&self) -> usize {
+        if self.packet_info { HEADER_LEN } else { 0 }
+    }
+
+    /// Returns the length of the header the kernel puts in front of each
+    /// packet, which a utun fd always has, even when `read` strips it.
+    pub(crate) fn kernel_header_len(&self) -> usize {
+        HEADER_LEN
+    }
+
+    /// Returns the number of packets read from and written to this stream.
+    pub fn stats(&self) -> QueueStat {
+        self.counters.get()
+    }
+
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub(crate) fn discards_writes(&self) -> bool {
+        self.discard_writes
+    }
+
+    pub(crate) fn pool(&self) -> Option<&BufferPool> {
+        self.pool.as_ref()
+    }
+
     /// Shuts down the read, write, or both halves of this connection.
     ///
     /// This function will cause all pending and future I/O on the specified
@@ -100,6 +470,99 @@ impl UtunStream {
     }
 }
 
+impl UtunBuilder {
+    /// Creates a builder for the interface `name` with the default options.
+    ///
+    /// `name` is either `utunN`, or `tunN` as used on Linux; both open the
+    /// interface `utunN`. A bare `utun` or `tun` lets the kernel pick the next
+    /// free unit.
+    pub fn new(name: &str) -> Self {
+        UtunBuilder {
+            name: name.to_string(),
+            packet_info: true,
+            nonblocking: true,
+            buffer_pool: None,
+        }
+    }
+
+    /// Sets whether packets read from the device keep the 4-byte address
+    /// family header that utun prepends.
+    ///
+    /// utun has no kernel option to omit the header, so when disabled it is
+    /// split off into a scratch buffer on every read and the caller's buffer
+    /// holds only the IP packet, as with `IFF_NO_PI` on Linux. Writes always
+    /// take a bare IP packet. Defaults to `true`.
+    pub fn packet_info(&mut self, enabled: bool) -> &mut Self {
+        self.packet_info = enabled;
+        self
+    }
+
+    /// Sets whether the device is opened in non-blocking mode, as required
+    /// for use with mio. Defaults to `true`.
+    pub fn nonblocking(&mut self, nonblocking: bool) -> &mut Self {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Gives the stream a pool of `capacity` buffers of `buf_size` bytes,
+    /// which `UtunStream::recv` reads packets into.
+    ///
+    /// `buf_size` should leave room for the packet header on top of the MTU.
+    pub fn buffer_pool(&mut self, capacity: usize, buf_size: usize) -> &mut Self {
+        self.buffer_pool = Some((capacity, buf_size));
+        self
+    }
+
+    /// Opens the utun device with the configured options.
+    pub fn connect(&self) -> io::Result<UtunStream> {
+        let name = &self.name;
+        validate_name(name)?;
+
+        let num = match name.strip_prefix("utun").or_else(|| name.strip_prefix("tun")) {
+            Some(num) => num,
+            None => return Err(io::ErrorKind::AddrNotAvailable.into()),
+        };
+
+        let unit: u32 = if num.is_empty() {
+            0
+        } else {
+            num.parse::<u32>().ok().and_then(utun_unit_for).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "bad utun interface number")
+            })?
+        };
+
+        let fd: RawFd = socket(AddressFamily::System,
+                               SockType::Datagram,
+                               SockFlag::empty(),
+                               SockProtocol::KextControl)
+            .map_err(io::Error::other)?;
+
+        let addr = SockAddr::new_sys_control(fd,
+                                             "com.apple.net.utun_control",
+                                             unit)
+            .map_err(io::Error::other)?;
+
+        if self.nonblocking {
+            fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+                .map_err(io::Error::other)?;
+        }
+
+        connect(fd, &addr)
+            .map_err(io::Error::other)?;
+
+        Ok(UtunStream {
+            fd,
+            packet_info: self.packet_info,
+            counters: Counters::default(),
+            pool: self.buffer_pool.map(|(capacity, buf_size)| BufferPool::new(capacity, buf_size)),
+            discard_writes: false,
+            unknown_policy: UnknownPacketPolicy::Error,
+            keepalive: None,
+            timestamps_enabled: AtomicBool::new(false),
+        })
+    }
+}
+
 impl Drop for UtunStream {
     fn drop(&mut self) {
         // Ignore error...
@@ -109,41 +572,31 @@ impl Drop for UtunStream {
 
 impl Read for UtunStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        read(self.fd, buf)
-            .map_err(|e|
-                match e {
-                    nix::Error::Sys(Errno::EAGAIN) => io::ErrorKind::WouldBlock.into(),
-                    _ => io::Error::new(io::ErrorKind::Other, e)
-                })
+        (&*self).read(buf)
     }
 }
 
-impl<'a> Read for &'a UtunStream {
+impl Read for &UtunStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        read(self.fd, buf)
-            .map_err(|e|
-                match e {
-                    nix::Error::Sys(Errno::EAGAIN) => io::ErrorKind::WouldBlock.into(),
-                    _ => io::Error::new(io::ErrorKind::Other, e)
-                })
+        let res = if self.packet_info {
+            read(self.fd, buf)
+        } else {
+            let mut header = [0u8; 4];
+            readv(self.fd, &mut [IoVec::from_mut_slice(&mut header),
+                                 IoVec::from_mut_slice(buf)])
+                .map(|len| len.saturating_sub(header.len()))
+        };
+
+        let len = res.map_err(io_error)?;
+
+        self.counters.count_rx();
+        Ok(len)
     }
 }
 
 impl Write for UtunStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if buf.len() == 0 {
-            return Ok(0);
-        }
-
-        match buf[0] >> 4 {
-            4 => write(self.fd, &[&[0u8, 0x00, 0x00, 0x02], buf].concat()),
-            6 => write(self.fd, &[&[0u8, 0x00, 0x00, 0x1e], buf].concat()),
-            _ => return Err(io::Error::new(io::ErrorKind::Other, "unrecognized IP version")),
-        }.map(|len| len - 4)
-        .map_err(|e| match e {
-            nix::Error::Sys(Errno::EAGAIN) => io::ErrorKind::WouldBlock.into(),
-            _ => io::Error::new(io::ErrorKind::Other, e)
-        })
+        (&*self).write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -151,21 +604,9 @@ impl Write for UtunStream {
     }
 }
 
-impl<'a> Write for &'a UtunStream {
+impl Write for &UtunStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if buf.len() == 0 {
-            return Ok(0);
-        }
-
-        match buf[0] >> 4 {
-            4 => write(self.fd, &[&[0u8, 0x00, 0x00, 0x02], buf].concat()),
-            6 => write(self.fd, &[&[0u8, 0x00, 0x00, 0x1e], buf].concat()),
-            _ => return Err(io::Error::new(io::ErrorKind::Other, "unrecognized IP version")),
-        }.map(|len| len - 4)
-        .map_err(|e| match e {
-            nix::Error::Sys(Errno::EAGAIN) => io::ErrorKind::WouldBlock.into(),
-            _ => io::Error::new(io::ErrorKind::Other, e)
-        })
+        self.write_into(&mut Vec::new(), buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -205,6 +646,15 @@ impl IntoRawFd for UtunStream {
 
 impl FromRawFd for UtunStream {
     unsafe fn from_raw_fd(fd: RawFd) -> Self {
-        Self { fd }
+        Self {
+            fd,
+            packet_info: true,
+            counters: Counters::default(),
+            pool: None,
+            discard_writes: false,
+            unknown_policy: UnknownPacketPolicy::Error,
+            keepalive: None,
+            timestamps_enabled: AtomicBool::new(false),
+        }
     }
 }
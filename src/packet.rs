@@ -0,0 +1,320 @@
+//! Helpers for building and rewriting IP packets.
+
+use std::io;
+use std::net::SocketAddr;
+
+/// Computes the Internet checksum (RFC 1071) of `data`.
+///
+/// The checksum of a header whose checksum field is zeroed is the value to
+/// store in that field; summing over a header with a valid checksum gives 0.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += u32::from(word);
+    }
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Recomputes and stores the header checksum of the IPv4 packet `packet`.
+pub(crate) fn update_ipv4_checksum(packet: &mut [u8]) {
+    let ihl = usize::from(packet[0] & 0x0f) * 4;
+    packet[10] = 0;
+    packet[11] = 0;
+    let sum = checksum(&packet[..ihl]);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Decrements the TTL of the IPv4 packet, or the hop limit of the IPv6
+/// packet, `packet` as a router forwarding it would, updating the IPv4
+/// header checksum incrementally (RFC 1624).
+///
+/// Returns `false`, leaving the packet untouched, if the TTL has run out and
+/// the packet must be dropped rather than forwarded; a router would answer
+/// it with an ICMP time exceeded message.
+pub fn decrement_ttl(packet: &mut [u8]) -> io::Result<bool> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+    match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            if packet[8] <= 1 {
+                return Ok(false);
+            }
+            let old = u16::from_be_bytes([packet[8], packet[9]]);
+            packet[8] -= 1;
+            let new = u16::from_be_bytes([packet[8], packet[9]]);
+
+            // HC' = ~(~HC + ~m + m')
+            let hc = u16::from_be_bytes([packet[10], packet[11]]);
+            let mut sum = u32::from(!hc) + u32::from(!old) + u32::from(new);
+            while sum >> 16 != 0 {
+                sum = (sum & 0xffff) + (sum >> 16);
+            }
+            packet[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+            Ok(true)
+        }
+        Some(6) if packet.len() >= 40 => {
+            if packet[7] <= 1 {
+                return Ok(false);
+            }
+            packet[7] -= 1;
+            Ok(true)
+        }
+        _ => Err(invalid("not an IP packet")),
+    }
+}
+
+/// Builds an IPv4 or IPv6 packet carrying a UDP datagram from `src` to `dst`,
+/// with the IP and UDP checksums filled in.
+///
+/// Returns an error if `src` and `dst` aren't of the same family or the
+/// payload doesn't fit in a datagram.
+pub fn udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+    let udp_len = 8 + payload.len();
+    if udp_len > usize::from(u16::MAX) - 40 {
+        return Err(invalid("payload too large for a UDP datagram"));
+    }
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let mut packet = match (src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            let total_len = (20 + udp_len) as u16;
+            let mut header = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 17, 0, 0];
+            header[2..4].copy_from_slice(&total_len.to_be_bytes());
+            header.extend_from_slice(&src.ip().octets());
+            header.extend_from_slice(&dst.ip().octets());
+            update_ipv4_checksum(&mut header);
+            header
+        }
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+            let mut header = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+            header[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+            header.extend_from_slice(&src.ip().octets());
+            header.extend_from_slice(&dst.ip().octets());
+            header
+        }
+        _ => return Err(invalid("source and destination families differ")),
+    };
+
+    // A computed checksum of zero is sent as all ones, as zero means none.
+    let sum = match transport_checksum(&packet, 17, &udp) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+    packet.extend_from_slice(&udp);
+    Ok(packet)
+}
+
+/// Splits the TCP segment in the IPv4 or IPv6 packet `packet` into segments
+/// carrying at most `mss` bytes of payload each.
+///
+/// Each segment gets a copy of the IP and TCP headers with its sequence
+/// number, lengths and checksums updated; FIN and PSH are only kept on the
+/// last segment, and CWR on the first. IPv6 packets with extension headers
+/// aren't supported. Returns an error if the packet isn't a well-formed TCP
+/// packet or `mss` is zero.
+pub fn segment_tcp(packet: &[u8], mss: u16) -> io::Result<Vec<Vec<u8>>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+    let (v6, ip_len, total_len, proto) = match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            let ihl = usize::from(packet[0] & 0x0f) * 4;
+            (false, ihl, usize::from(u16::from_be_bytes([packet[2], packet[3]])), packet[9])
+        }
+        Some(6) if packet.len() >= 40 => {
+            (true, 40, 40 + usize::from(u16::from_be_bytes([packet[4], packet[5]])), packet[6])
+        }
+        _ => return Err(invalid("not an IP packet")),
+    };
+    if proto != 6 {
+        return Err(invalid("not a TCP packet"));
+    }
+    if ip_len < 20 || total_len < ip_len + 20 || total_len > packet.len() {
+        return Err(invalid("malformed IP header"));
+    }
+    let tcp = &packet[ip_len..total_len];
+    let tcp_len = usize::from(tcp[12] >> 4) * 4;
+    if tcp_len < 20 || tcp_len > tcp.len() {
+        return Err(invalid("malformed TCP header"));
+    }
+    if mss == 0 {
+        return Err(invalid("MSS must be non-zero"));
+    }
+
+    let payload = &tcp[tcp_len..];
+    if payload.len() <= usize::from(mss) {
+        return Ok(vec![packet[..total_len].to_vec()]);
+    }
+
+    let ip_header = &packet[..ip_len];
+    let tcp_header = &tcp[..tcp_len];
+    let seq = u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]);
+
+    let mut segments = Vec::new();
+    for (i, chunk) in payload.chunks(usize::from(mss)).enumerate() {
+        let off = i * usize::from(mss);
+        let last = off + chunk.len() == payload.len();
+
+        let mut segment = Vec::with_capacity(ip_len + tcp_len + chunk.len());
+        segment.extend_from_slice(ip_header);
+        segment.extend_from_slice(tcp_header);
+        segment.extend_from_slice(chunk);
+
+        if v6 {
+            // The payload length doesn't count the fixed header.
+            let payload_len = (segment.len() - 40) as u16;
+            segment[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        } else {
+            let total_len = segment.len() as u16;
+            segment[2..4].copy_from_slice(&total_len.to_be_bytes());
+            let id = u16::from_be_bytes([segment[4], segment[5]]).wrapping_add(i as u16);
+            segment[4..6].copy_from_slice(&id.to_be_bytes());
+            update_ipv4_checksum(&mut segment);
+        }
+
+        let tcp = &mut segment[ip_len..];
+        tcp[4..8].copy_from_slice(&seq.wrapping_add(off as u32).to_be_bytes());
+        if !last {
+            // FIN and PSH
+            tcp[13] &= !0x09;
+        }
+        if i > 0 {
+            // CWR
+            tcp[13] &= !0x80;
+        }
+        tcp[16] = 0;
+        tcp[17] = 0;
+
+        let sum = transport_checksum(&segment[..ip_len], 6, &segment[ip_len..]);
+        segment[ip_len + 16..ip_len + 18].copy_from_slice(&sum.to_be_bytes());
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+/// Computes the checksum of the TCP or UDP `segment` carried with protocol
+/// number `proto` in a packet with the IPv4 or IPv6 header `ip_header`,
+/// covering the pseudo-header built from it.
+fn transport_checksum(ip_header: &[u8], proto: u8, segment: &[u8]) -> u16 {
+    let mut pseudo = Vec::with_capacity(40 + segment.len());
+    if ip_header[0] >> 4 == 4 {
+        pseudo.extend_from_slice(&ip_header[12..20]);
+        pseudo.extend_from_slice(&[0, proto]);
+        pseudo.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    } else {
+        pseudo.extend_from_slice(&ip_header[8..40]);
+        pseudo.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, proto]);
+    }
+    pseudo.extend_from_slice(segment);
+    checksum(&pseudo)
+}
+
+/// Splits the IPv4 packet `packet` into fragments of at most `mtu` bytes.
+///
+/// Each fragment gets its own header with the fragment offset, the
+/// more-fragments flag and the checksum updated. Options are only carried
+/// into the first fragment, except those flagged to be copied into all of
+/// them. Returns an error if the packet is malformed, has the don't-fragment
+/// flag set, or the MTU can't fit a header and eight bytes of payload.
+pub fn fragment_ipv4(packet: &[u8], mtu: usize) -> io::Result<Vec<Vec<u8>>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return Err(invalid("not an IPv4 packet"));
+    }
+    let ihl = usize::from(packet[0] & 0x0f) * 4;
+    let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    if ihl < 20 || total_len < ihl || total_len > packet.len() {
+        return Err(invalid("malformed IPv4 header"));
+    }
+
+    if total_len <= mtu {
+        return Ok(vec![packet[..total_len].to_vec()]);
+    }
+
+    let frag = u16::from_be_bytes([packet[6], packet[7]]);
+    if frag & 0x4000 != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+    }
+    let more = frag & 0x2000;
+    let base_offset = usize::from(frag & 0x1fff) * 8;
+
+    let first_header = &packet[..ihl];
+    let rest_header = copied_header(first_header);
+    let payload = &packet[ihl..total_len];
+
+    let mut fragments = Vec::new();
+    let mut off = 0;
+    while off < payload.len() {
+        let header = if off == 0 { first_header } else { &rest_header[..] };
+        let max = mtu.saturating_sub(header.len()) & !7;
+        if max == 0 {
+            return Err(invalid("MTU too small to fragment into"));
+        }
+
+        let end = (off + max).min(payload.len());
+        let mut fragment = Vec::with_capacity(header.len() + end - off);
+        fragment.extend_from_slice(header);
+        fragment.extend_from_slice(&payload[off..end]);
+
+        let len = fragment.len() as u16;
+        let offset = ((base_offset + off) / 8) as u16;
+        let flags = if end < payload.len() { 0x2000 } else { more };
+        fragment[2..4].copy_from_slice(&len.to_be_bytes());
+        fragment[6..8].copy_from_slice(&(flags | offset).to_be_bytes());
+        update_ipv4_checksum(&mut fragment);
+
+        fragments.push(fragment);
+        off = end;
+    }
+    Ok(fragments)
+}
+
+/// Returns a copy of the IPv4 header `header` keeping only the options that
+/// must be copied into every fragment.
+fn copied_header(header: &[u8]) -> Vec<u8> {
+    let mut out = header[..20].to_vec();
+    let mut i = 20;
+    while i < header.len() {
+        let kind = header[i];
+        match kind {
+            0 => break,
+            1 => i += 1,
+            _ => {
+                let len = usize::from(*header.get(i + 1).unwrap_or(&0));
+                if len < 2 || i + len > header.len() {
+                    break;
+                }
+                if kind & 0x80 != 0 {
+                    out.extend_from_slice(&header[i..i + len]);
+                }
+                i += len;
+            }
+        }
+    }
+
+    // Pad the options with end-of-list bytes to a multiple of 4.
+    while !out.len().is_multiple_of(4) {
+        out.push(0);
+    }
+    out[0] = 0x40 | (out.len() / 4) as u8;
+    out
+}
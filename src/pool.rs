@@ -0,0 +1,90 @@
+//! Reusable packet buffers for allocation-free reads.
+
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// A pool of equally-sized packet buffers, shared between a stream and the
+/// `PacketBuf`s it hands out.
+#[derive(Debug, Clone)]
+pub(crate) struct BufferPool {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    free: Mutex<Vec<Vec<u8>>>,
+    capacity: usize,
+    buf_size: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool which keeps up to `capacity` buffers of `buf_size`
+    /// bytes around for reuse.
+    pub(crate) fn new(capacity: usize, buf_size: usize) -> Self {
+        let free = (0..capacity).map(|_| vec![0u8; buf_size]).collect();
+        BufferPool {
+            inner: Arc::new(Inner {
+                free: Mutex::new(free),
+                capacity,
+                buf_size,
+            }),
+        }
+    }
+
+    /// Takes a buffer out of the pool, allocating a new one if it is empty.
+    pub(crate) fn take(&self) -> PacketBuf {
+        let buf = self.inner.free.lock().unwrap().pop()
+            .unwrap_or_else(|| vec![0u8; self.inner.buf_size]);
+        PacketBuf { buf, len: 0, pool: self.clone() }
+    }
+
+    fn put(&self, buf: Vec<u8>) {
+        let mut free = self.inner.free.lock().unwrap();
+        if free.len() < self.inner.capacity {
+            free.push(buf);
+        }
+    }
+}
+
+/// A packet held in a buffer borrowed from a stream's pool.
+///
+/// Dereferences to the bytes of the packet, and returns its memory to the
+/// pool when dropped.
+#[derive(Debug)]
+pub struct PacketBuf {
+    buf: Vec<u8>,
+    len: usize,
+    pool: BufferPool,
+}
+
+impl PacketBuf {
+    /// The whole underlying buffer, to read a packet into.
+    pub(crate) fn spare(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    pub(crate) fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
+}
+
+impl Deref for PacketBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl DerefMut for PacketBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+impl Drop for PacketBuf {
+    fn drop(&mut self) {
+        self.pool.put(mem::take(&mut self.buf));
+    }
+}
@@ -0,0 +1,125 @@
+//! Token-bucket shaping of the packets written to a tunnel.
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use UtunStream;
+
+/// The rate a `RateLimited` stream may write at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    /// A number of packets per second, whatever their size.
+    PacketsPerSecond(u64),
+    /// A number of bytes of packet payload per second.
+    BytesPerSecond(u64),
+}
+
+impl Budget {
+    fn rate(self) -> f64 {
+        match self {
+            Budget::PacketsPerSecond(n) | Budget::BytesPerSecond(n) => n as f64,
+        }
+    }
+}
+
+/// A stream whose writes are held to a `Budget`.
+///
+/// The bucket holds one second's worth of budget, so up to that much can be
+/// written in a burst. Once it's spent, writes to a non-blocking stream fail
+/// with `WouldBlock`, and writes to a blocking one sleep until it refills.
+/// Only successful writes consume the budget.
+#[derive(Debug)]
+pub struct RateLimited {
+    stream: UtunStream,
+    budget: Budget,
+    blocking: bool,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimited {
+    /// Wraps `stream`, starting with a full bucket. Whether writes block is
+    /// taken from the stream's `O_NONBLOCK` flag.
+    ///
+    /// Returns an `InvalidInput` error if the budget is zero.
+    pub fn new(stream: UtunStream, budget: Budget) -> io::Result<Self> {
+        if budget.rate() <= 0.0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "rate limit budget must be non-zero"));
+        }
+
+        let flags = fcntl(stream.as_raw_fd(), FcntlArg::F_GETFL)
+            .map_err(io::Error::other)?;
+        let blocking = !OFlag::from_bits_truncate(flags).contains(OFlag::O_NONBLOCK);
+
+        Ok(RateLimited {
+            stream,
+            budget,
+            blocking,
+            tokens: budget.rate(),
+            last: Instant::now(),
+        })
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &UtunStream {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut UtunStream {
+        &mut self.stream
+    }
+
+    /// Unwraps the underlying stream.
+    pub fn into_inner(self) -> UtunStream {
+        self.stream
+    }
+
+    /// Returns how many tokens writing `buf` costs, capped at the bucket size
+    /// so that packets larger than the budget still get through eventually.
+    fn cost(&self, buf: &[u8]) -> f64 {
+        match self.budget {
+            Budget::PacketsPerSecond(_) => 1.0,
+            Budget::BytesPerSecond(_) => (buf.len() as f64).min(self.budget.rate()),
+        }
+    }
+
+    /// Refills the bucket, returning how long until `cost` tokens are
+    /// available, if they aren't already.
+    fn wait_time(&mut self, cost: f64) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.budget.rate()).min(self.budget.rate());
+        self.last = now;
+
+        if self.tokens >= cost {
+            None
+        } else {
+            Some(Duration::from_secs_f64((cost - self.tokens) / self.budget.rate()))
+        }
+    }
+}
+
+impl Write for RateLimited {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let cost = self.cost(buf);
+        while let Some(wait) = self.wait_time(cost) {
+            if !self.blocking {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            thread::sleep(wait);
+        }
+
+        let len = (&self.stream).write(buf)?;
+        self.tokens -= cost;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+//! A stream that reopens its tunnel when the interface goes away.
+
+use libc;
+use mio::event::Evented;
+use mio::unix::EventedFd;
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+
+use std::fmt;
+use std::io::{self, Read, Write};
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use {errno_of, UtunBuilder, UtunStream};
+
+/// A stream that transparently reconnects when its tunnel fails.
+///
+/// When a read or write fails because the interface is gone or the fd is no
+/// longer usable, the stream is reopened from the builder it was created
+/// with, retrying with exponential backoff, and the operation is retried
+/// once on the new stream. Reconnecting sleeps between attempts, blocking
+/// the calling thread.
+///
+/// Poll registrations survive reconnecting. The stream registers a
+/// user-space `mio::Registration` rather than its fd, and a thread, started
+/// on first registration, forwards the readiness of whichever fd is current.
+///
+/// On Linux, the MTU, IPv4 address and up state set through this stream are
+/// re-applied to each new interface before `on_reconnect` runs. Anything
+/// configured on the interface by other means has to be re-applied in the
+/// callback.
+pub struct ReconnectingUtun {
+    builder: UtunBuilder,
+    stream: UtunStream,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_attempts: usize,
+    on_reconnect: Option<Box<ReconnectFn>>,
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    config: Config,
+    bridge: Mutex<Option<Bridge>>,
+}
+
+/// The interface configuration to re-apply after reconnecting.
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+#[derive(Debug, Default)]
+struct Config {
+    mtu: Option<u32>,
+    ipv4: Option<(Ipv4Addr, Ipv4Addr)>,
+    up: bool,
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+impl Config {
+    fn apply(&self, stream: &UtunStream) -> io::Result<()> {
+        if let Some(mtu) = self.mtu {
+            stream.set_mtu(mtu)?;
+        }
+        if let Some((addr, netmask)) = self.ipv4 {
+            stream.set_ipv4_address(addr, netmask)?;
+        }
+        if self.up {
+            stream.up()?;
+        }
+        Ok(())
+    }
+}
+
+/// Tokens in the bridge's own poll.
+const STREAM: Token = Token(0);
+const SHUTDOWN: Token = Token(1);
+
+/// Forwards the readiness of the current fd to `registration`, which is
+/// what the caller's poll sees.
+///
+/// The fd is registered level-triggered and oneshot, and only for the
+/// interest that isn't already reported, so an fd that stays writable
+/// doesn't keep the thread busy. A bit is cleared again once a read or
+/// write would block, and the fd re-armed for it; level triggering reports
+/// it straight away if it became ready in between.
+struct Bridge {
+    registration: Registration,
+    shared: Arc<Shared>,
+    _shutdown_registration: Registration,
+    shutdown: SetReadiness,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// The part of the bridge its thread uses too.
+struct Shared {
+    poll: Poll,
+    readiness: SetReadiness,
+    // Held while updating `readiness` and re-arming, so the two stay in step.
+    state: Mutex<State>,
+}
+
+struct State {
+    fd: RawFd,
+    interest: Ready,
+}
+
+impl Bridge {
+    fn new(stream: &UtunStream, interest: Ready) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let (registration, readiness) = Registration::new2();
+        let (shutdown_registration, shutdown) = Registration::new2();
+        poll.register(&shutdown_registration, SHUTDOWN, Ready::readable(), PollOpt::edge())?;
+        poll.register(stream, STREAM, interest, PollOpt::level() | PollOpt::oneshot())?;
+
+        let shared = Arc::new(Shared {
+            poll,
+            readiness,
+            state: Mutex::new(State { fd: stream.as_raw_fd(), interest }),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("mio-utun-reconnect".into())
+                .spawn(move || shared.forward())?
+        };
+
+        Ok(Bridge {
+            registration,
+            shared,
+            _shutdown_registration: shutdown_registration,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// Clears `ready` and re-arms the fd's registration for it.
+    fn rearm(&self, ready: Ready) -> io::Result<()> {
+        let state = self.shared.state.lock().unwrap();
+        self.shared.update(&state, self.shared.readiness.readiness() - ready)
+    }
+
+    /// Changes the interest the fd is watched for.
+    fn set_interest(&self, interest: Ready) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.interest = interest;
+        self.shared.update(&state, self.shared.readiness.readiness() & interest)
+    }
+
+    /// Starts watching `stream`, the fd of a new connection, in place of the
+    /// old one, which went away when it was closed.
+    fn replace(&self, stream: &UtunStream) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.fd = stream.as_raw_fd();
+        self.shared.readiness.set_readiness(Ready::empty())?;
+        self.shared.poll.register(stream, STREAM, state.interest, PollOpt::level() | PollOpt::oneshot())
+    }
+}
+
+impl Shared {
+    /// Sets the reported readiness to `ready` and arms the fd for the rest
+    /// of the interest.
+    fn update(&self, state: &State, ready: Ready) -> io::Result<()> {
+        self.readiness.set_readiness(ready)?;
+        let pending = state.interest - ready;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        self.poll.reregister(&EventedFd(&state.fd), STREAM, pending, PollOpt::level() | PollOpt::oneshot())
+    }
+
+    /// Runs the bridge's thread until it's told to stop.
+    fn forward(&self) {
+        let mut events = Events::with_capacity(4);
+        loop {
+            match self.poll.poll(&mut events, None) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            }
+            for event in &events {
+                if event.token() == SHUTDOWN {
+                    return;
+                }
+                let state = self.state.lock().unwrap();
+                let ready = self.readiness.readiness() | (event.readiness() & state.interest);
+                // A failure means the fd was closed, and is replaced.
+                let _ = self.update(&state, ready);
+            }
+        }
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        let _ = self.shutdown.set_readiness(Ready::readable());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+type ReconnectFn = dyn FnMut(&UtunStream) -> io::Result<()> + Send;
+
+impl ReconnectingUtun {
+    /// Connects with `builder`, keeping it to reconnect with later.
+    ///
+    /// Reconnection defaults to 10 attempts, backing off from 100ms to 10s.
+    pub fn new(builder: UtunBuilder) -> io::Result<Self> {
+        let stream = builder.connect()?;
+        Ok(ReconnectingUtun {
+            builder,
+            stream,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: 10,
+            on_reconnect: None,
+            #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+            config: Config::default(),
+            bridge: Mutex::new(None),
+        })
+    }
+
+    /// Sets the delay before the first reconnection attempt, which doubles
+    /// after each failed attempt up to `max`.
+    pub fn backoff(&mut self, initial: Duration, max: Duration) -> &mut Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Sets how many times to try reconnecting before giving up and
+    /// returning the last connect error.
+    pub fn max_attempts(&mut self, attempts: usize) -> &mut Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Sets a callback run with each new stream before it's used, after the
+    /// configuration set through this stream was re-applied.
+    ///
+    /// An error from the callback counts as a failed attempt.
+    pub fn on_reconnect<F>(&mut self, f: F) -> &mut Self
+        where F: FnMut(&UtunStream) -> io::Result<()> + Send + 'static
+    {
+        self.on_reconnect = Some(Box::new(f));
+        self
+    }
+
+    /// Returns a reference to the current stream.
+    pub fn get_ref(&self) -> &UtunStream {
+        &self.stream
+    }
+
+    /// Replaces the stream with a newly connected one, backing off between
+    /// failed attempts.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        let mut delay = self.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            let res = self.builder.connect().and_then(|stream| {
+                #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+                self.config.apply(&stream)?;
+                if let Some(ref mut f) = self.on_reconnect {
+                    f(&stream)?;
+                }
+                Ok(stream)
+            });
+
+            match res {
+                Ok(stream) => {
+                    self.stream = stream;
+                    if let Some(ref bridge) = *self.bridge.get_mut().unwrap() {
+                        bridge.replace(&self.stream)?;
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    attempts += 1;
+                    if attempts >= self.max_attempts {
+                        return Err(e);
+                    }
+                }
+            }
+
+            thread::sleep(delay);
+            delay = (delay * 2).min(self.max_backoff);
+        }
+    }
+
+    /// Runs `f` on the stream, reconnecting and running it once more if it
+    /// fails fatally.
+    fn retry<T, F>(&mut self, mut f: F) -> io::Result<T>
+        where F: FnMut(&UtunStream) -> io::Result<T>
+    {
+        match f(&self.stream) {
+            Err(ref e) if is_fatal(e) => {
+                self.reconnect()?;
+                f(&self.stream)
+            }
+            res => res,
+        }
+    }
+
+    /// Re-arms the poll registration for `ready` if `res` says the stream
+    /// would block.
+    fn rearm<T>(&mut self, res: &io::Result<T>, ready: Ready) {
+        if let Err(ref e) = *res {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                if let Some(ref bridge) = *self.bridge.get_mut().unwrap() {
+                    let _ = bridge.rearm(ready);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+impl ReconnectingUtun {
+    /// Sets the interface's MTU, now and after each reconnect.
+    pub fn set_mtu(&mut self, mtu: u32) -> io::Result<()> {
+        self.stream.set_mtu(mtu)?;
+        self.config.mtu = Some(mtu);
+        Ok(())
+    }
+
+    /// Sets the interface's IPv4 address and netmask, now and after each
+    /// reconnect.
+    pub fn set_ipv4_address(&mut self, addr: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+        self.stream.set_ipv4_address(addr, netmask)?;
+        self.config.ipv4 = Some((addr, netmask));
+        Ok(())
+    }
+
+    /// Brings the interface up, now and after each reconnect.
+    pub fn up(&mut self) -> io::Result<()> {
+        self.stream.up()?;
+        self.config.up = true;
+        Ok(())
+    }
+
+    /// Takes the interface down, and leaves new interfaces down after
+    /// reconnecting.
+    pub fn down(&mut self) -> io::Result<()> {
+        self.stream.down()?;
+        self.config.up = false;
+        Ok(())
+    }
+}
+
+/// Returns whether `err` means the stream is beyond use and has to be
+/// reopened.
+fn is_fatal(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::NotConnected | io::ErrorKind::ConnectionAborted => return true,
+        _ => {}
+    }
+
+    match errno_of(err) {
+        Some(libc::ENXIO) | Some(libc::ENODEV) | Some(libc::EPIPE) => true,
+        // A tun fd whose interface was deleted.
+        #[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+        Some(libc::EBADFD) => true,
+        _ => false,
+    }
+}
+
+impl fmt::Debug for ReconnectingUtun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReconnectingUtun")
+            .field("builder", &self.builder)
+            .field("stream", &self.stream)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("max_attempts", &self.max_attempts)
+            .finish()
+    }
+}
+
+impl Read for ReconnectingUtun {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = self.retry(|mut stream| stream.read(buf));
+        self.rearm(&res, Ready::readable());
+        res
+    }
+}
+
+impl Write for ReconnectingUtun {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = self.retry(|mut stream| stream.write(buf));
+        self.rearm(&res, Ready::writable());
+        res
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for ReconnectingUtun {
+    fn register(&self, poll: &Poll, token: Token,
+                events: Ready, opts: PollOpt) -> io::Result<()> {
+        let mut bridge = self.bridge.lock().unwrap();
+        match *bridge {
+            Some(ref bridge) => bridge.set_interest(events)?,
+            None => *bridge = Some(Bridge::new(&self.stream, events)?),
+        }
+        poll.register(&bridge.as_ref().unwrap().registration, token, events, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token,
+                  events: Ready, opts: PollOpt) -> io::Result<()> {
+        let bridge = self.bridge.lock().unwrap();
+        let bridge = bridge.as_ref().ok_or(io::ErrorKind::NotFound)?;
+        bridge.set_interest(events)?;
+        poll.reregister(&bridge.registration, token, events, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        let bridge = self.bridge.lock().unwrap();
+        let bridge = bridge.as_ref().ok_or(io::ErrorKind::NotFound)?;
+        poll.deregister(&bridge.registration)
+    }
+}
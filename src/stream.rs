@@ -0,0 +1,603 @@
+//! The parts of `UtunStream` shared by every platform, and the helpers built
+//! on it.
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::ifaddrs::getifaddrs;
+use nix::poll::{poll, EventFlags, PollFd};
+use nix::sys::socket::{AddressFamily, SockAddr};
+use nix::sys::uio::{readv, writev, IoVec};
+
+use mio::{Poll, PollOpt, Ready, Token};
+
+use pool::BufferPool;
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::IpAddr;
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use {framing, interests, packet, IpVersion, PacketBuf, TunnelDescriptor, UtunStream, HEADER_LEN};
+
+impl IpVersion {
+    /// Returns the version in the first nibble of `packet`, if it's IPv4 or
+    /// IPv6.
+    pub(crate) fn of(packet: &[u8]) -> Option<Self> {
+        framing::ip_version(packet)
+    }
+}
+
+/// What `write` does with a packet that isn't IPv4 or IPv6 by its first
+/// nibble, as set with `UtunStream::set_unknown_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnknownPacketPolicy {
+    /// Drop the packet, reporting that nothing was written (`Ok(0)`).
+    Drop,
+    /// Fail the write with an error. This is the default.
+    Error,
+    /// Write the packet with the header for the given version.
+    Assume(IpVersion),
+}
+
+/// What `UtunStream::recv_detailed` learned about the packet it read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvInfo {
+    /// The length of the packet read into the buffer, without its header.
+    pub payload_len: usize,
+    /// The length of the header that preceded it.
+    pub header_len: usize,
+    /// Whether the packet didn't fit in the buffer and was cut short.
+    pub truncated: bool,
+    /// The packet's IP version, if it's IPv4 or IPv6.
+    pub version: Option<IpVersion>,
+}
+
+/// Converts an error from a read or write on the tunnel fd.
+///
+/// `EAGAIN` becomes `WouldBlock`, and `EBADF` becomes `NotConnected` so that
+/// a closed fd can be told apart from other failures.
+pub(crate) fn io_error(e: nix::Error) -> io::Error {
+    match e {
+        nix::Error::Sys(Errno::EAGAIN) => io::ErrorKind::WouldBlock.into(),
+        nix::Error::Sys(Errno::EBADF) => io::Error::new(io::ErrorKind::NotConnected, "tunnel fd is closed or invalid"),
+        _ => io::Error::other(e)
+    }
+}
+
+/// Returns the errno behind `err`, whether it came straight from the OS or
+/// through `io_error`.
+pub(crate) fn errno_of(err: &io::Error) -> Option<i32> {
+    err.raw_os_error().or_else(|| {
+        match err.get_ref().and_then(|e| e.downcast_ref::<nix::Error>()) {
+            Some(&nix::Error::Sys(errno)) => Some(errno as i32),
+            _ => None,
+        }
+    })
+}
+
+/// Returns whether `err`, from a read or write, means the tunnel's interface
+/// was destroyed under the fd.
+fn interface_gone(err: &io::Error) -> bool {
+    match errno_of(err) {
+        Some(libc::ENODEV) => true,
+        // What Linux returns for a tun fd whose interface was deleted.
+        #[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+        Some(libc::EBADFD) => true,
+        _ => false,
+    }
+}
+
+/// Checks that `name` can be used as an interface name, returning an
+/// `InvalidInput` error if it's empty, longer than `IFNAMSIZ - 1` bytes, or
+/// contains a NUL or non-ASCII character.
+///
+/// Both platforms' `connect` run this before opening anything.
+pub fn validate_name(name: &str) -> io::Result<()> {
+    let problem = if name.is_empty() {
+        "interface name is empty"
+    } else if name.len() > libc::IFNAMSIZ - 1 {
+        "interface name is too long"
+    } else if name.bytes().any(|b| b == 0 || !b.is_ascii()) {
+        "interface name contains a NUL or non-ASCII character"
+    } else {
+        return Ok(());
+    };
+    Err(io::Error::new(io::ErrorKind::InvalidInput, problem))
+}
+
+/// Decodes the 4-byte header macOS puts in front of each utun packet.
+///
+/// The header is the packet's address family as a big-endian `u32`, using
+/// the Darwin values `AF_INET` (2) and `AF_INET6` (30). Returns `None` for any
+/// other value or a header shorter than 4 bytes. This is plain byte decoding,
+/// so it works on any platform.
+pub fn macos_header_family(header: &[u8]) -> Option<AddressFamily> {
+    if header.len() < 4 {
+        return None;
+    }
+
+    match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+        2 => Some(AddressFamily::Inet),
+        30 => Some(AddressFamily::Inet6),
+        _ => None,
+    }
+}
+
+/// A reader which strips the packet header from everything read from the
+/// wrapped stream, leaving only the IP packet in the caller's buffer.
+///
+/// The buffer passed to `read` still needs room for the header, which is
+/// read into it and then shifted out. Streams opened without packet
+/// information are passed through unchanged.
+#[derive(Debug)]
+pub struct DecapReader<'a>(pub &'a UtunStream);
+
+impl<'a> Read for DecapReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let header_len = self.0.header_len();
+        let len = (&*self.0).read(buf)?;
+        if len < header_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "packet shorter than its header"));
+        }
+
+        buf.copy_within(header_len..len, 0);
+        Ok(len - header_len)
+    }
+}
+
+/// Opens the tunnel `name`, runs `f` with it and closes it again, even if `f`
+/// panics.
+///
+/// On Linux the interface is taken down first, on a best-effort basis. The
+/// kernel removes an interface the stream created as soon as its fd is
+/// closed, but one made persistent beforehand (`ip tuntap add ... persist`)
+/// outlives it and would otherwise be left up. A panic in `f` is resumed once
+/// the tunnel is closed.
+pub fn with_tunnel<R, F>(name: &str, f: F) -> io::Result<R>
+    where F: FnOnce(&UtunStream) -> R
+{
+    let stream = UtunStream::connect(name)?;
+    let res = panic::catch_unwind(AssertUnwindSafe(|| f(&stream)));
+    #[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+    let _ = stream.down();
+    drop(stream);
+
+    match res {
+        Ok(r) => Ok(r),
+        Err(e) => panic::resume_unwind(e),
+    }
+}
+
+/// The headers the kernel expects in front of IPv4 and IPv6 packets.
+#[cfg(all(unix, any(target_os = "macos", target_os = "ios")))]
+const EXPECTED_HEADERS: [[u8; 4]; 2] = [[0, 0, 0, 0x02], [0, 0, 0, 0x1e]];
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+const EXPECTED_HEADERS: [[u8; 4]; 2] = [[0, 0, 0x08, 0x00], [0, 0, 0x86, 0xdd]];
+
+/// Checks that packets are framed the way this platform's kernel expects.
+///
+/// Packets can't be looped back through a real tunnel, so a known IPv4 and
+/// IPv6 packet are written through a `UtunStream` backed by a socketpair and
+/// the bytes arriving at the other end are compared against the expected
+/// header and payload. Returns an `InvalidData` error on any mismatch.
+pub fn self_test() -> io::Result<()> {
+    let (ours, theirs) = UnixDatagram::pair()?;
+    let mut stream = unsafe { UtunStream::from_raw_fd(ours.into_raw_fd()) };
+
+    let mut v4 = [0u8; 20];
+    v4[0] = 0x45;
+    let mut v6 = [0u8; 40];
+    v6[0] = 0x60;
+
+    let mut buf = [0u8; 64];
+    for (packet, header) in [&v4[..], &v6[..]].iter().zip(EXPECTED_HEADERS.iter()) {
+        let written = stream.write(packet)?;
+        let len = theirs.recv(&mut buf)?;
+        if written != packet.len() || len < 4 || &buf[..4] != header || &buf[4..len] != *packet {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected packet framing"));
+        }
+    }
+    Ok(())
+}
+
+/// Per-stream packet counts, as returned by `UtunStream::stats` and
+/// `queue_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStat {
+    /// Packets successfully read from the stream.
+    pub rx_packets: u64,
+    /// Packets successfully written to the stream.
+    pub tx_packets: u64,
+}
+
+/// Reports the packet counts of each stream in `streams`, such as the queues
+/// of a multiqueue device.
+///
+/// The kernel doesn't expose per-queue counters, so these are kept by the
+/// crate and only cover packets read and written through each `UtunStream`.
+pub fn queue_stats(streams: &[UtunStream]) -> io::Result<Vec<QueueStat>> {
+    Ok(streams.iter().map(UtunStream::stats).collect())
+}
+
+/// The packet counters kept by each stream.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    rx_packets: AtomicU64,
+    tx_packets: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn count_rx(&self) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_tx(&self) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> QueueStat {
+        QueueStat {
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl UtunStream {
+    /// Takes ownership of a tunnel fd opened elsewhere, such as the one
+    /// Android's `VpnService` provides.
+    pub fn from_owned_fd(fd: OwnedFd) -> UtunStream {
+        unsafe { UtunStream::from_raw_fd(fd.into_raw_fd()) }
+    }
+
+    /// Takes ownership of `fd` after checking that it refers to a tun (Linux)
+    /// or utun (macOS) device, by asking the kernel for its interface name.
+    ///
+    /// If the check fails an `InvalidInput` error is returned and `fd` is
+    /// closed.
+    pub fn from_owned_fd_checked(fd: OwnedFd) -> io::Result<UtunStream> {
+        let stream = UtunStream::from_owned_fd(fd);
+        match stream.name() {
+            Ok(_) => Ok(stream),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "fd is not a tun device")),
+        }
+    }
+
+    /// Takes ownership of `fd`, received from the process that described it
+    /// as `desc`, restoring the framing the sender used.
+    ///
+    /// A utun fd doesn't record its framing, and a tun fd only does where
+    /// sysfs can be read, so the descriptor's takes precedence. The interface
+    /// isn't reconfigured from `desc`; it's assumed to still be as the sender
+    /// left it.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open tunnel fd that nothing else owns, as for
+    /// `from_raw_fd`.
+    pub unsafe fn from_fd_and_descriptor(fd: RawFd, desc: TunnelDescriptor) -> UtunStream {
+        let mut stream = UtunStream::from_raw_fd(fd);
+        stream.set_packet_info(desc.packet_info);
+        stream
+    }
+
+    /// Describes the tunnel's current configuration, to send along with its
+    /// fd to another process.
+    pub fn describe(&self) -> io::Result<TunnelDescriptor> {
+        Ok(TunnelDescriptor {
+            name: self.name()?,
+            mtu: self.mtu()?,
+            addresses: self.addresses()?,
+            flags: self.flags()?,
+            packet_info: self.packet_info(),
+        })
+    }
+
+    /// Returns the IPv4 and IPv6 addresses configured on the interface, as
+    /// listed by `getifaddrs`.
+    pub fn addresses(&self) -> io::Result<Vec<IpAddr>> {
+        let name = self.name()?;
+        let addrs = getifaddrs().map_err(io::Error::other)?
+            .filter(|ifaddr| ifaddr.interface_name == name)
+            .filter_map(|ifaddr| match ifaddr.address {
+                Some(SockAddr::Inet(addr)) => Some(addr.to_std().ip()),
+                _ => None,
+            })
+            .collect();
+        Ok(addrs)
+    }
+
+    /// Returns the file status flags of the fd (`F_GETFL`), such as its
+    /// access mode and `O_NONBLOCK`.
+    pub fn status_flags(&self) -> io::Result<OFlag> {
+        let flags = fcntl(self.as_raw_fd(), FcntlArg::F_GETFL).map_err(io::Error::other)?;
+        Ok(OFlag::from_bits_truncate(flags))
+    }
+
+    /// Returns the fd flags (`F_GETFD`), i.e. whether `FD_CLOEXEC` is set.
+    pub fn fd_flags(&self) -> io::Result<FdFlag> {
+        let flags = fcntl(self.as_raw_fd(), FcntlArg::F_GETFD).map_err(io::Error::other)?;
+        Ok(FdFlag::from_bits_truncate(flags))
+    }
+
+    /// Reads one packet into a buffer sized for the interface MTU and returns
+    /// it without its header, along with its IP version.
+    pub fn read_to_vec(&self) -> io::Result<(Vec<u8>, IpVersion)> {
+        let mut buf = vec![0u8; self.header_len() + self.mtu()? as usize];
+        let len = DecapReader(self).read(&mut buf)?;
+        buf.truncate(len);
+
+        match IpVersion::of(&buf) {
+            Some(version) => Ok((buf, version)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "unrecognized IP version")),
+        }
+    }
+
+    /// Reads one packet, header included, into a buffer from the stream's
+    /// pool.
+    ///
+    /// Without a pool configured on the `UtunBuilder`, a buffer sized for the
+    /// interface MTU is allocated for each packet instead.
+    pub fn recv(&self) -> io::Result<PacketBuf> {
+        let mut packet = match self.pool() {
+            Some(pool) => pool.take(),
+            None => BufferPool::new(0, self.header_len() + self.mtu()? as usize).take(),
+        };
+
+        let mut stream = self;
+        let len = stream.read(packet.spare())?;
+        packet.set_len(len);
+        Ok(packet)
+    }
+
+    /// Reads a packet like `read`, but tells a tunnel that has gone away apart
+    /// from one that simply has no packet ready.
+    ///
+    /// An error of kind `ConnectionAborted` is returned if the read fails
+    /// because the interface was destroyed (`EBADFD` or `ENODEV`), or if it
+    /// would block and polling the fd finds `POLLHUP`/`POLLERR` set.
+    pub fn read_checked(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut stream = self;
+        match stream.read(buf) {
+            Err(ref e) if interface_gone(e) => {
+                Err(io::Error::new(io::ErrorKind::ConnectionAborted, "tunnel interface is gone"))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let mut fds = [PollFd::new(self.as_raw_fd(), EventFlags::empty())];
+                poll(&mut fds, 0).map_err(io::Error::other)?;
+
+                let hangup = EventFlags::POLLHUP | EventFlags::POLLERR | EventFlags::POLLNVAL;
+                match fds[0].revents() {
+                    Some(revents) if revents.intersects(hangup) => {
+                        Err(io::Error::new(io::ErrorKind::ConnectionAborted, "tunnel hung up"))
+                    }
+                    _ => Err(io::ErrorKind::WouldBlock.into()),
+                }
+            }
+            res => res,
+        }
+    }
+
+    /// Registers the stream with `poll` for readable and writable events,
+    /// edge-triggered.
+    ///
+    /// With edge-triggering the stream must be read (or written) until it
+    /// returns `WouldBlock` before another event is delivered.
+    pub fn register_default(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        poll.register(self, token, interests::read_write(), PollOpt::edge())
+    }
+
+    /// Registers the stream with `poll` for `interests`, edge-triggered, and
+    /// returns a guard which deregisters it again when dropped.
+    ///
+    /// The guard borrows both the stream and the poll, so the stream can't be
+    /// dropped or replaced while it is still registered.
+    pub fn register_guarded<'a>(&'a self, poll: &'a Poll, token: Token, interests: Ready)
+                                -> io::Result<Registration<'a>> {
+        poll.register(self, token, interests, PollOpt::edge())?;
+        Ok(Registration { stream: self, poll })
+    }
+
+    /// Swaps the fd of a stream registered with `poll` for that of `new`, so
+    /// that event handlers keep their token across a reload.
+    ///
+    /// The old fd is deregistered and closed, and the new one is registered
+    /// under `token` with the interest of `register_default`.
+    pub fn replace_fd(&mut self, new: UtunStream, poll: &Poll, token: Token) -> io::Result<()> {
+        poll.deregister(self)?;
+        drop(mem::replace(self, new));
+        self.register_default(poll, token)
+    }
+
+    /// Writes `buf` prefixed with the caller-supplied `prefix` instead of the
+    /// header for its IP version, in a single `writev`.
+    ///
+    /// This allows driving encapsulations whose 4-byte control word isn't the
+    /// standard header. The prefix is written even if the stream was opened
+    /// without packet information. Returns the number of bytes of `buf`
+    /// written.
+    pub fn write_with_prefix(&self, prefix: [u8; 4], buf: &[u8]) -> io::Result<usize> {
+        if self.discards_writes() {
+            return Ok(buf.len());
+        }
+
+        let len = writev(self.as_raw_fd(), &[IoVec::from_slice(&prefix), IoVec::from_slice(buf)])
+            .map(|len| len.saturating_sub(prefix.len()))
+            .map_err(io_error)?;
+
+        self.counters().count_tx();
+        Ok(len)
+    }
+
+    /// Reads a packet's header into `header` and the packet itself into
+    /// `payload`, in a single `readv`, returning the packet's length.
+    ///
+    /// This is the read-side counterpart of `write_with_prefix`. Returns an
+    /// `InvalidInput` error if the kernel doesn't put exactly a `HEADER_LEN`
+    /// header in front of the stream's packets, such as a Linux tun opened
+    /// without packet information. A macOS utun always has one.
+    pub fn recv_split(&self, header: &mut [u8; HEADER_LEN], payload: &mut [u8]) -> io::Result<usize> {
+        if self.kernel_header_len() != HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "stream has no 4-byte packet header"));
+        }
+
+        let len = readv(self.as_raw_fd(), &mut [IoVec::from_mut_slice(header), IoVec::from_mut_slice(payload)])
+            .map_err(io_error)?;
+        if len < HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "packet shorter than its header"));
+        }
+
+        self.counters().count_rx();
+        Ok(len - HEADER_LEN)
+    }
+
+    /// Writes `packet`, first splitting it into IPv4 fragments if it is
+    /// larger than the interface MTU.
+    ///
+    /// Returns the length of `packet` once every fragment is written. An
+    /// oversized IPv6 packet, or IPv4 packet with the don't-fragment flag,
+    /// can't be fragmented on the way and fails with `EMSGSIZE`, just like
+    /// the kernel would.
+    pub fn send_fragmented(&self, packet: &[u8]) -> io::Result<usize> {
+        let mtu = self.mtu()? as usize;
+        let mut stream = self;
+        if packet.len() <= mtu {
+            return stream.write(packet);
+        }
+
+        match packet[0] >> 4 {
+            4 => {}
+            6 => return Err(io::Error::from_raw_os_error(libc::EMSGSIZE)),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unrecognized IP version")),
+        }
+
+        for fragment in packet::fragment_ipv4(packet, mtu)? {
+            stream.write_all(&fragment)?;
+        }
+        Ok(packet.len())
+    }
+
+    /// Forwards `packet` into the tunnel, first decrementing its TTL or hop
+    /// limit as a router would (see `packet::decrement_ttl`).
+    ///
+    /// Returns `Ok(0)` without writing anything if the TTL has run out, so the
+    /// caller can answer with an ICMP time exceeded message; otherwise the
+    /// length written, as with `write`.
+    pub fn write_forwarded(&self, packet: &mut [u8]) -> io::Result<usize> {
+        if !packet::decrement_ttl(packet)? {
+            return Ok(0);
+        }
+        let mut stream = self;
+        stream.write(packet)
+    }
+
+    /// Writes the TCP packet `packet`, first splitting it into segments of
+    /// at most `mss` bytes of payload if it's larger, for when the device
+    /// has no segmentation offload.
+    ///
+    /// Returns the length of `packet` once every segment is written.
+    pub fn send_segmented(&self, packet: &[u8], mss: u16) -> io::Result<usize> {
+        let mut stream = self;
+        for segment in packet::segment_tcp(packet, mss)? {
+            stream.write_all(&segment)?;
+        }
+        Ok(packet.len())
+    }
+
+    /// Writes each complete packet in `framed`, a buffer of packets that are
+    /// each prefixed with their length as a big-endian `u16`.
+    ///
+    /// Returns the number of packets written; the bytes consumed are those
+    /// packets plus their prefixes. A trailing partial frame is left for the
+    /// next call, and writing stops early if the device would block. An error
+    /// is only returned if no packet could be written.
+    pub fn write_lenprefixed(&self, framed: &[u8]) -> io::Result<usize> {
+        let mut stream = self;
+        let mut rest = framed;
+        let mut count = 0;
+        while rest.len() >= 2 {
+            let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            if rest.len() < 2 + len {
+                break;
+            }
+
+            match stream.write(&rest[2..2 + len]) {
+                Ok(_) => {}
+                Err(_) if count > 0 => break,
+                Err(e) => return Err(e),
+            }
+            count += 1;
+            rest = &rest[2 + len..];
+        }
+        Ok(count)
+    }
+
+    /// Writes an empty UDP datagram between the endpoints set with
+    /// `set_keepalive`, to keep a NAT mapping for the tunnel alive.
+    ///
+    /// Returns an error of kind `InvalidInput` if no endpoints are set.
+    pub fn send_keepalive(&self) -> io::Result<()> {
+        let (src, dst) = self.keepalive().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no keepalive endpoints set")
+        })?;
+        let mut stream = self;
+        stream.write_all(&packet::udp_packet(src, dst, &[])?)
+    }
+
+    /// Reads as many packets as fit into `buf`, back to back and without
+    /// their headers, and returns the range each one occupies.
+    ///
+    /// Reading stops once the device would block or the space left couldn't
+    /// hold a packet of the interface MTU. An error is only returned if no
+    /// packet could be read.
+    pub fn read_packets_into(&self, buf: &mut [u8]) -> io::Result<Vec<Range<usize>>> {
+        let room = self.header_len() + self.mtu()? as usize;
+        let mut ranges = Vec::new();
+        let mut offset = 0;
+        while buf.len() - offset >= room {
+            match DecapReader(self).read(&mut buf[offset..]) {
+                Ok(len) => {
+                    ranges.push(offset..offset + len);
+                    offset += len;
+                }
+                Err(_) if !ranges.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ranges)
+    }
+}
+
+/// A registration of a `UtunStream` with a `Poll`, which is deregistered when
+/// the guard is dropped.
+///
+/// Returned by `UtunStream::register_guarded`.
+#[derive(Debug)]
+pub struct Registration<'a> {
+    stream: &'a UtunStream,
+    poll: &'a Poll,
+}
+
+impl<'a> Drop for Registration<'a> {
+    fn drop(&mut self) {
+        // Ignore error...
+        let _ = self.poll.deregister(self.stream);
+    }
+}
+
+impl From<OwnedFd> for UtunStream {
+    /// Equivalent to `UtunStream::from_owned_fd`.
+    fn from(fd: OwnedFd) -> UtunStream {
+        UtunStream::from_owned_fd(fd)
+    }
+}
+
+impl From<UtunStream> for OwnedFd {
+    fn from(stream: UtunStream) -> OwnedFd {
+        unsafe { OwnedFd::from_raw_fd(stream.into_raw_fd()) }
+    }
+}
@@ -0,0 +1,419 @@
+//! A minimal AF_XDP socket bound to the tunnel's interface.
+//!
+//! This sets up the UMEM and the four rings and hands them out as-is. It
+//! doesn't load an XDP program, which is needed for the kernel to redirect
+//! received packets into the rx ring; attach one that redirects into an
+//! `XSKMAP` holding `XskSocket::as_raw_fd`. Transmitting needs no program.
+
+use libc;
+use mio::event::Evented;
+use mio::unix::EventedFd;
+use mio::{Poll, PollOpt, Ready, Token};
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use UtunStream;
+
+// From linux/if_xdp.h, which libc only covers in recent versions.
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+
+const XDP_COPY: u16 = 1 << 1;
+
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+
+const XDP_PGOFF_RX_RING: u64 = 0;
+const XDP_PGOFF_TX_RING: u64 = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: u64 = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: u64 = 0x180000000;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct sockaddr_xdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+/// The original layout, which every kernel with AF_XDP accepts.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct xdp_umem_reg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct xdp_ring_offset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct xdp_mmap_offsets {
+    rx: xdp_ring_offset,
+    tx: xdp_ring_offset,
+    fr: xdp_ring_offset,
+    cr: xdp_ring_offset,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct xdp_desc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+/// The sizes of the UMEM and rings set up by `UtunStream::bind_xsk`.
+///
+/// The kernel requires `frame_size` to be a power of two from 2048 up to
+/// the page size, and `ring_size` to be a power of two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XskConfig {
+    /// The queue of the interface to bind to.
+    pub queue_id: u32,
+    /// The size of each frame of the UMEM.
+    pub frame_size: u32,
+    /// The number of frames in the UMEM.
+    pub frame_count: u32,
+    /// The number of entries in each of the four rings.
+    pub ring_size: u32,
+}
+
+impl Default for XskConfig {
+    fn default() -> Self {
+        XskConfig {
+            queue_id: 0,
+            frame_size: 2048,
+            frame_count: 4096,
+            ring_size: 2048,
+        }
+    }
+}
+
+/// A packet in the UMEM, as carried by the rx and tx rings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XskDesc {
+    /// The offset of the packet into the UMEM.
+    pub addr: u64,
+    /// The length of the packet.
+    pub len: u32,
+}
+
+/// An AF_XDP socket with its UMEM and rings, returned by
+/// `UtunStream::bind_xsk`.
+///
+/// Frames are handed to the kernel through the fill ring (for receiving)
+/// and the tx ring (for sending), and come back through the rx and
+/// completion rings respectively. Which frames are free is up to the
+/// caller.
+pub struct XskSocket {
+    fill: Ring,
+    completion: Ring,
+    rx: Ring,
+    tx: Ring,
+    umem: Mmap,
+    fd: RawFd,
+}
+
+// The mappings are owned by the socket, and the rings are only touched
+// through `&mut self`.
+unsafe impl Send for XskSocket {}
+
+impl UtunStream {
+    /// Creates an AF_XDP socket bound to queue `config.queue_id` of the
+    /// tunnel's interface, with a UMEM and rings of the configured sizes.
+    ///
+    /// The socket is bound in copy mode, as tun devices don't support
+    /// zero-copy. Requires `CAP_NET_RAW`.
+    pub fn bind_xsk(&self, config: XskConfig) -> io::Result<XskSocket> {
+        let ifindex = self.ifindex()?;
+
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = Fd(fd);
+
+        let umem_len = config.frame_size as usize * config.frame_count as usize;
+        let umem = Mmap::new(-1, umem_len, 0, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS)?;
+
+        let mut reg: xdp_umem_reg = unsafe { mem::zeroed() };
+        reg.addr = umem.ptr as u64;
+        reg.len = umem_len as u64;
+        reg.chunk_size = config.frame_size;
+        setsockopt(fd, XDP_UMEM_REG, &reg)?;
+
+        for &opt in &[XDP_UMEM_FILL_RING, XDP_UMEM_COMPLETION_RING,
+                      XDP_RX_RING, XDP_TX_RING] {
+            setsockopt(fd, opt, &config.ring_size)?;
+        }
+
+        let mut off: xdp_mmap_offsets = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<xdp_mmap_offsets>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(fd, SOL_XDP, XDP_MMAP_OFFSETS,
+                &mut off as *mut _ as *mut libc::c_void, &mut len)
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let size = config.ring_size;
+        let addr_len = mem::size_of::<u64>();
+        let desc_len = mem::size_of::<xdp_desc>();
+        let fill = Ring::new(fd, &off.fr, size, addr_len, XDP_UMEM_PGOFF_FILL_RING)?;
+        let completion = Ring::new(fd, &off.cr, size, addr_len,
+                                   XDP_UMEM_PGOFF_COMPLETION_RING)?;
+        let rx = Ring::new(fd, &off.rx, size, desc_len, XDP_PGOFF_RX_RING)?;
+        let tx = Ring::new(fd, &off.tx, size, desc_len, XDP_PGOFF_TX_RING)?;
+
+        let mut addr: sockaddr_xdp = unsafe { mem::zeroed() };
+        addr.sxdp_family = AF_XDP as u16;
+        addr.sxdp_flags = XDP_COPY;
+        addr.sxdp_ifindex = ifindex;
+        addr.sxdp_queue_id = config.queue_id;
+        let res = unsafe {
+            libc::bind(fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<sockaddr_xdp>() as libc::socklen_t)
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(XskSocket { fill, completion, rx, tx, umem, fd: sock.into_raw() })
+    }
+}
+
+impl XskSocket {
+    /// Returns the UMEM, which the ring descriptors point into.
+    pub fn umem(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.umem.ptr as *const u8, self.umem.len) }
+    }
+
+    /// Returns the UMEM mutably, for writing packets to send.
+    pub fn umem_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.umem.ptr as *mut u8, self.umem.len) }
+    }
+
+    /// Hands the frames at `addrs` to the kernel to receive into, returning
+    /// how many fit in the fill ring.
+    pub fn fill(&mut self, addrs: &[u64]) -> usize {
+        self.fill.produce(addrs.len(), |ring, i, idx| unsafe {
+            *ring.entry::<u64>(idx) = addrs[i];
+        })
+    }
+
+    /// Takes up to `descs.len()` received packets off the rx ring, returning
+    /// how many were taken.
+    pub fn recv(&mut self, descs: &mut [XskDesc]) -> usize {
+        self.rx.consume(descs.len(), |ring, i, idx| unsafe {
+            let desc = &*ring.entry::<xdp_desc>(idx);
+            descs[i] = XskDesc { addr: desc.addr, len: desc.len };
+        })
+    }
+
+    /// Queues the packets in `descs` on the tx ring, returning how many fit.
+    ///
+    /// Call `wakeup` to have the kernel send them.
+    pub fn send(&mut self, descs: &[XskDesc]) -> usize {
+        self.tx.produce(descs.len(), |ring, i, idx| unsafe {
+            *ring.entry::<xdp_desc>(idx) = xdp_desc {
+                addr: descs[i].addr,
+                len: descs[i].len,
+                options: 0,
+            };
+        })
+    }
+
+    /// Takes up to `addrs.len()` sent frames off the completion ring,
+    /// returning how many were taken. Those frames are free to reuse.
+    pub fn complete(&mut self, addrs: &mut [u64]) -> usize {
+        self.completion.consume(addrs.len(), |ring, i, idx| unsafe {
+            addrs[i] = *ring.entry::<u64>(idx);
+        })
+    }
+
+    /// Kicks the kernel into sending what's queued on the tx ring.
+    pub fn wakeup(&self) -> io::Result<()> {
+        let res = unsafe { libc::sendto(self.fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // The kernel is still busy with an earlier batch.
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => Ok(()),
+                _ => Err(err),
+            }
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for XskSocket {
+    fn drop(&mut self) {
+        // The mappings are dropped after this, and hold on to the socket
+        // until they're gone.
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl AsRawFd for XskSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Evented for XskSocket {
+    fn register(&self, poll: &Poll, token: Token,
+                events: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.fd).register(poll, token, events, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token,
+                  events: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.fd).reregister(poll, token, events, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+/// Sets the `SOL_XDP` option `opt` to `value`.
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, value: &T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(fd, SOL_XDP, opt,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t)
+    };
+    if res != 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// An fd that's closed on drop unless it's taken back out.
+struct Fd(RawFd);
+
+impl Fd {
+    fn into_raw(self) -> RawFd {
+        let fd = self.0;
+        mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// A read-write mapping that's unmapped on drop.
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: u64, flags: libc::c_int) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       flags | libc::MAP_POPULATE, fd, offset as libc::off_t)
+        };
+        if ptr == libc::MAP_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Mmap { ptr, len })
+        }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// One of the single-producer, single-consumer rings shared with the
+/// kernel. The indices run freely and are masked to find an entry.
+struct Ring {
+    map: Mmap,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: usize,
+    mask: u32,
+}
+
+impl Ring {
+    fn new(fd: RawFd, off: &xdp_ring_offset, size: u32, entry_len: usize,
+           pgoff: u64) -> io::Result<Self> {
+        let len = off.desc as usize + size as usize * entry_len;
+        let map = Mmap::new(fd, len, pgoff, libc::MAP_SHARED)?;
+        let base = map.ptr as *const u8;
+        Ok(Ring {
+            producer: unsafe { base.add(off.producer as usize) } as *const AtomicU32,
+            consumer: unsafe { base.add(off.consumer as usize) } as *const AtomicU32,
+            desc: off.desc as usize,
+            mask: size - 1,
+            map,
+        })
+    }
+
+    /// Returns a pointer to the entry at ring index `idx`.
+    unsafe fn entry<T>(&self, idx: u32) -> *mut T {
+        let base = (self.map.ptr as *mut u8).add(self.desc) as *mut T;
+        base.add((idx & self.mask) as usize)
+    }
+
+    /// Writes up to `n` entries with `f`, as the producer, and publishes
+    /// them to the kernel.
+    fn produce<F: FnMut(&Ring, usize, u32)>(&mut self, n: usize, mut f: F) -> usize {
+        let (prod, cons) = unsafe {
+            ((*self.producer).load(Ordering::Relaxed), (*self.consumer).load(Ordering::Acquire))
+        };
+        let free = (self.mask + 1 - prod.wrapping_sub(cons)) as usize;
+        let n = n.min(free);
+        for i in 0..n {
+            f(self, i, prod.wrapping_add(i as u32));
+        }
+        unsafe { (*self.producer).store(prod.wrapping_add(n as u32), Ordering::Release) };
+        n
+    }
+
+    /// Reads up to `n` entries with `f`, as the consumer, and releases them
+    /// back to the kernel.
+    fn consume<F: FnMut(&Ring, usize, u32)>(&mut self, n: usize, mut f: F) -> usize {
+        let (prod, cons) = unsafe {
+            ((*self.producer).load(Ordering::Acquire), (*self.consumer).load(Ordering::Relaxed))
+        };
+        let n = n.min(prod.wrapping_sub(cons) as usize);
+        for i in 0..n {
+            f(self, i, cons.wrapping_add(i as u32));
+        }
+        unsafe { (*self.consumer).store(cons.wrapping_add(n as u32), Ordering::Release) };
+        n
+    }
+}
@@ -1,11 +1,16 @@
+#![cfg(feature = "std")]
+
+extern crate libc;
 extern crate mio;
 extern crate mio_utun;
+extern crate nix;
 
-use std::io::{self, Write, Read};
-use std::io::ErrorKind::WouldBlock;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::time::Duration;
 
 use mio::{Poll, PollOpt, Events, Ready, Token};
-use mio_utun::UtunStream;
+use mio_utun::{with_tunnel, UtunBuilder, UtunStream};
 
 macro_rules! t {
     ($e:expr) => (match $e {
@@ -16,8 +21,6 @@ macro_rules! t {
 
 #[test]
 fn smoke() {
-    const SERVER: Token = Token(0);
-
     println!("connecting");
     let mut utun = UtunStream::connect("utun6").unwrap();
     let mut buf = [0u8; 1500];
@@ -28,6 +31,17 @@ fn smoke() {
     assert_eq!(utun.name().unwrap(), "utun6");
 }
 
+#[test]
+fn no_packet_info() {
+    let mut utun = t!(UtunBuilder::new("utun7").packet_info(false).connect());
+    let mut buf = [0u8; 1500];
+    match utun.read(&mut buf) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        _ => panic!("should have WouldBlock'd")
+    }
+    assert_eq!(t!(utun.name()), "utun7");
+}
+
 #[test]
 fn test_server() {
     const SERVER: Token = Token(0);
@@ -48,3 +62,569 @@ fn test_server() {
     println!("read {} bytes!", len);
 }
 
+
+#[test]
+fn not_running() {
+    let utun = t!(UtunStream::connect("utun8"));
+    match utun.wait_until_running(Duration::from_millis(50)) {
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+        _ => panic!("a down interface shouldn't be running")
+    }
+}
+
+#[test]
+fn blocking() {
+    let utun = t!(UtunBuilder::new("utun9").nonblocking(false).connect());
+    let flags = unsafe { libc::fcntl(utun.as_raw_fd(), libc::F_GETFL) };
+    assert_eq!(flags & libc::O_NONBLOCK, 0);
+}
+
+#[test]
+fn scoped_teardown() {
+    let res = std::panic::catch_unwind(|| {
+        t!(with_tunnel("utun10", |_| panic!("oops")))
+    });
+    assert!(res.is_err());
+
+    // The interface must be free again for a fresh owner.
+    let name = t!(with_tunnel("utun10", |utun| t!(utun.name())));
+    assert_eq!(name, "utun10");
+}
+
+#[test]
+fn default_mtu() {
+    let utun = t!(UtunStream::connect("utun11"));
+    assert_eq!(t!(utun.mtu()), 1500);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn fwmark() {
+    let socket = t!(std::net::UdpSocket::bind("127.0.0.1:0"));
+    t!(mio_utun::linux::set_fwmark(&socket, 0x1234));
+}
+
+#[test]
+fn default_registration() {
+    let utun = t!(UtunStream::connect("utun12"));
+    let poll = t!(Poll::new());
+    t!(utun.register_default(&poll, Token(0)));
+    t!(poll.deregister(&utun));
+}
+
+#[test]
+fn pooled_recv() {
+    let utun = t!(UtunBuilder::new("utun13").buffer_pool(2, 1504).connect());
+    match utun.recv() {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        _ => panic!("should have WouldBlock'd")
+    }
+}
+
+#[test]
+fn checked_adoption() {
+    let fd = OwnedFd::from(t!(UtunStream::connect("utun14")));
+    let utun = t!(UtunStream::from_owned_fd_checked(fd));
+    assert_eq!(t!(utun.name()), "utun14");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn carrier() {
+    let utun = t!(UtunStream::connect("utun15"));
+    assert!(!t!(utun.carrier()));
+    match utun.wait_carrier_change(Duration::from_millis(50)) {
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+        r => panic!("unexpected link change: {:?}", r)
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn tap_frames() {
+    let tun = t!(UtunStream::connect("utun16"));
+    let frame = [0u8; 60];
+    assert_eq!(tun.send_ethernet(&frame).unwrap_err().kind(), io::ErrorKind::Unsupported);
+
+    let tap = t!(UtunBuilder::new("utap0").tap(true).connect());
+    assert_eq!(tap.send_ethernet(&frame[..10]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(tap.send_ethernet_with_fcs(&frame[..16]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn tap_strips_fcs() {
+    let tap = t!(UtunBuilder::new("utap2").tap(true).connect());
+    t!(tap.up());
+    let mut frame = [0u8; 64];
+    frame[..6].copy_from_slice(&[0xff; 6]);
+    frame[12..14].copy_from_slice(&[0x08, 0x06]);
+    assert_eq!(t!(tap.send_ethernet_with_fcs(&frame)), 60);
+}
+
+#[test]
+fn replace_registered_fd() {
+    let mut utun = t!(UtunStream::connect("utun17"));
+    let poll = t!(Poll::new());
+    t!(utun.register_default(&poll, Token(3)));
+
+    t!(utun.replace_fd(t!(UtunStream::connect("utun18")), &poll, Token(3)));
+    assert_eq!(t!(utun.name()), "utun18");
+
+    // The old interface was closed along with its fd.
+    let _ = t!(UtunStream::connect("utun17"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn configured_addresses() {
+    use std::net::IpAddr;
+    use std::process::Command;
+
+    let utun = t!(UtunStream::connect("utun19"));
+    assert!(t!(utun.addresses()).is_empty());
+
+    let status = t!(Command::new("ip").args(["addr", "add", "10.26.0.1/24", "dev", "utun19"]).status());
+    assert!(status.success());
+    assert_eq!(t!(utun.addresses()), vec!["10.26.0.1".parse::<IpAddr>().unwrap()]);
+}
+
+#[test]
+fn guarded_registration() {
+    let utun = t!(UtunStream::connect("utun20"));
+    let poll = t!(Poll::new());
+    {
+        let _registration = t!(utun.register_guarded(&poll, Token(0), Ready::readable()));
+    }
+
+    // Dropping the guard deregistered the stream, so it can be registered anew.
+    t!(poll.register(&utun, Token(0), Ready::readable(), PollOpt::edge()));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn vnet_hdr_flag() {
+    use std::fs;
+
+    let _utun = t!(UtunBuilder::new("utun21").vnet_hdr(true).connect());
+    let flags = t!(fs::read_to_string("/sys/class/net/utun21/tun_flags"));
+    let flags = u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).unwrap();
+    assert_ne!(flags & 0x4000, 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn custom_device_path() {
+    use std::path::Path;
+
+    let res = UtunBuilder::new("utun22").device_path(Path::new("/nonexistent/tun")).connect();
+    assert!(res.is_err());
+    let utun = t!(UtunBuilder::new("utun22").device_path(Path::new("/dev/net/tun")).connect());
+    assert_eq!(t!(utun.name()), "utun22");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reconnects_deleted_interface() {
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use mio_utun::ReconnectingUtun;
+
+    let reconnects = Arc::new(AtomicUsize::new(0));
+    let counter = reconnects.clone();
+    let mut utun = t!(ReconnectingUtun::new(UtunBuilder::new("utun23")));
+    utun.backoff(Duration::from_millis(1), Duration::from_millis(10))
+        .on_reconnect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+    let status = t!(Command::new("ip").args(["link", "del", "utun23"]).status());
+    assert!(status.success());
+
+    let mut buf = [0u8; 1500];
+    match utun.read(&mut buf) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        r => panic!("expected a fresh, empty stream: {:?}", r)
+    }
+    assert_eq!(reconnects.load(Ordering::SeqCst), 1);
+    assert_eq!(t!(utun.get_ref().name()), "utun23");
+}
+
+#[test]
+fn packets_into_buffer() {
+    let utun = t!(UtunStream::connect("utun24"));
+    let mut buf = [0u8; 4096];
+    match utun.read_packets_into(&mut buf) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        r => panic!("should have WouldBlock'd: {:?}", r)
+    }
+
+    // Too little room to be sure of fitting a packet.
+    assert!(t!(utun.read_packets_into(&mut buf[..1000])).is_empty());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn adoption_keeps_framing() {
+    use std::process::Command;
+
+    let fd = OwnedFd::from(t!(UtunBuilder::new("utun25").packet_info(false).connect()));
+    let mut utun = t!(UtunStream::from_owned_fd_checked(fd));
+
+    // Bringing the interface up makes the kernel send IPv6 router
+    // solicitations through it.
+    let status = t!(Command::new("ip").args(["link", "set", "utun25", "up"]).status());
+    assert!(status.success());
+
+    let poll = t!(Poll::new());
+    t!(poll.register(&utun, Token(0), Ready::readable(), PollOpt::edge()));
+    let mut events = Events::with_capacity(16);
+    t!(poll.poll(&mut events, Some(Duration::from_secs(5))));
+
+    let mut buf = [0u8; 1500];
+    t!(utun.read(&mut buf));
+    assert_eq!(buf[0] >> 4, 6);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn adoption_detects_packet_info() {
+    use std::process::Command;
+
+    let fd = OwnedFd::from(t!(UtunStream::connect("utun37")));
+    let mut utun = t!(UtunStream::from_owned_fd_checked(fd));
+
+    let status = t!(Command::new("ip").args(["link", "set", "utun37", "up"]).status());
+    assert!(status.success());
+
+    let poll = t!(Poll::new());
+    t!(poll.register(&utun, Token(0), Ready::readable(), PollOpt::edge()));
+    let mut events = Events::with_capacity(16);
+    t!(poll.poll(&mut events, Some(Duration::from_secs(5))));
+
+    let mut buf = [0u8; 1500];
+    t!(utun.read(&mut buf));
+    assert_eq!(&buf[..4], &[0, 0, 0x86, 0xdd]);
+}
+
+#[test]
+fn toggles_arp() {
+    let utun = t!(UtunStream::connect("utun26"));
+    t!(utun.set_arp(false));
+    assert!(!t!(utun.arp()));
+    t!(utun.set_arp(true));
+    assert!(t!(utun.arp()));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn down_oper_state() {
+    use mio_utun::linux::OperState;
+
+    let utun = t!(UtunStream::connect("utun27"));
+    assert_eq!(t!(utun.oper_state()), OperState::Down);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn tap_promiscuous() {
+    let tun = t!(UtunStream::connect("utun28"));
+    assert_eq!(tun.set_promiscuous(true).unwrap_err().kind(), io::ErrorKind::Unsupported);
+
+    let tap = t!(UtunBuilder::new("utap1").tap(true).connect());
+    t!(tap.set_promiscuous(true));
+    assert!(t!(tap.promiscuous()));
+    t!(tap.set_promiscuous(false));
+    assert!(!t!(tap.promiscuous()));
+}
+
+#[test]
+fn drains_tx() {
+    let utun = t!(UtunStream::connect("utun29"));
+    t!(utun.drain_tx(Duration::from_millis(50)));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn steering_program() {
+    let utun = t!(UtunStream::connect("utun30"));
+    // Detaching works even when no program is attached; a bogus fd doesn't.
+    t!(utun.set_steering_program(-1));
+    assert!(utun.set_steering_program(utun.as_raw_fd()).is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn one_call_setup() {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    let utun = t!(UtunStream::setup("utun31", Ipv4Addr::new(10, 31, 0, 1), Ipv4Addr::new(255, 255, 255, 0), 1400));
+    assert_eq!(t!(utun.mtu()), 1400);
+    assert!(t!(utun.addresses()).contains(&IpAddr::V4(Ipv4Addr::new(10, 31, 0, 1))));
+    assert_ne!(t!(utun.flags()) & mio_utun::linux::IFF_UP, 0);
+
+    // An MTU the kernel refuses rolls the interface back.
+    let res = UtunStream::setup("utun32", Ipv4Addr::new(10, 32, 0, 1), Ipv4Addr::new(255, 255, 255, 0), 10);
+    assert!(res.is_err());
+    assert!(!std::path::Path::new("/sys/class/net/utun32").exists());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn connect_ifreq() {
+    use std::mem;
+    use mio_utun::linux::{ifreq, IFF_NO_PI, IFF_TUN};
+
+    assert_eq!(mem::size_of::<ifreq>(), mem::size_of::<libc::ifreq>());
+
+    let utun = t!(UtunBuilder::new("utun33").packet_info(false).connect());
+    let req = utun.last_ifreq();
+    assert_eq!(&req[..7], b"utun33\0");
+    let flags = i16::from_ne_bytes([req[16], req[17]]);
+    assert_eq!(flags & (IFF_TUN | IFF_NO_PI), IFF_TUN | IFF_NO_PI);
+}
+
+#[test]
+fn descriptor_flags() {
+    let utun = t!(UtunStream::connect("utun34"));
+    let flags = t!(utun.status_flags());
+    assert!(flags.contains(nix::fcntl::OFlag::O_NONBLOCK));
+    #[cfg(target_os = "linux")]
+    assert!(flags.contains(nix::fcntl::OFlag::O_RDWR));
+    t!(utun.fd_flags());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn interface_index() {
+    let utun = t!(UtunStream::connect("utun35"));
+    let index = t!(utun.ifindex());
+    let sysfs = t!(std::fs::read_to_string("/sys/class/net/utun35/ifindex"));
+    assert_eq!(sysfs.trim(), index.to_string());
+}
+
+#[test]
+fn describe_and_adopt() {
+    let utun = t!(UtunStream::connect("utun36"));
+    let desc = t!(utun.describe());
+    assert_eq!(desc.name, "utun36");
+    assert_eq!(desc.mtu, t!(utun.mtu()));
+    assert!(desc.packet_info);
+
+    let fd = unsafe { libc::dup(utun.as_raw_fd()) };
+    assert!(fd >= 0);
+    let adopted = unsafe { UtunStream::from_fd_and_descriptor(fd, desc.clone()) };
+    assert_eq!(t!(adopted.describe()), desc);
+}
+
+#[test]
+fn empty_send_queue() {
+    let utun = t!(UtunStream::connect("utun38"));
+    assert_eq!(t!(utun.send_queue_len()), 0);
+}
+
+#[test]
+fn checks_existence() {
+    use mio_utun::interface_exists;
+
+    assert!(!interface_exists("utun39"));
+    let _utun = t!(UtunStream::connect("utun39"));
+    assert!(interface_exists("utun39"));
+    assert!(!interface_exists("../utun39"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn checked_read_after_delete() {
+    use std::process::Command;
+
+    let utun = t!(UtunStream::connect("utun40"));
+    let status = t!(Command::new("ip").args(["link", "del", "utun40"]).status());
+    assert!(status.success());
+
+    let mut buf = [0u8; 1500];
+    let err = utun.read_checked(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn split_read_needs_packet_info() {
+    let utun = t!(UtunBuilder::new("utun41").packet_info(false).connect());
+    let err = utun.recv_split(&mut [0; 4], &mut [0; 1500]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn scoped_teardown_takes_persistent_down() {
+    use std::process::Command;
+
+    let ip = |args: &[&str]| assert!(t!(Command::new("ip").args(args).status()).success());
+    ip(&["tuntap", "add", "dev", "utun42", "mode", "tun", "pi"]);
+    t!(with_tunnel("utun42", |utun| t!(utun.up())));
+
+    let flags = t!(std::fs::read_to_string("/sys/class/net/utun42/flags"));
+    ip(&["tuntap", "del", "dev", "utun42", "mode", "tun", "pi"]);
+    assert_eq!(i64::from_str_radix(flags.trim().trim_start_matches("0x"), 16).unwrap() & 1, 0);
+}
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+#[test]
+fn xsk_sends_into_tunnel() {
+    use mio_utun::{XskConfig, XskDesc};
+
+    let mut utun = t!(UtunBuilder::new("utun43").packet_info(false).nonblocking(false).connect());
+    t!(utun.up());
+    let mut xsk = t!(utun.bind_xsk(XskConfig { frame_count: 64, ring_size: 64, ..XskConfig::default() }));
+
+    // A bare IPv4 header, which tun hands back as-is.
+    let mut packet = [0u8; 20];
+    packet[0] = 0x45;
+    packet[3] = 20;
+    packet[8] = 64;
+    xsk.umem_mut()[..20].copy_from_slice(&packet);
+    assert_eq!(xsk.send(&[XskDesc { addr: 0, len: 20 }]), 1);
+    t!(xsk.wakeup());
+
+    // Skip anything the kernel sent on bringing the interface up.
+    let mut buf = [0u8; 1500];
+    loop {
+        let len = t!(utun.read(&mut buf));
+        if buf[..len] == packet[..] {
+            break;
+        }
+    }
+
+    let mut done = [0u64; 4];
+    assert_eq!(xsk.complete(&mut done), 1);
+    assert_eq!(done[0], 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reconnect_keeps_registration() {
+    use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+    use std::process::Command;
+    use mio_utun::ReconnectingUtun;
+
+    let mut utun = t!(ReconnectingUtun::new(UtunBuilder::new("utun44")));
+    utun.backoff(Duration::from_millis(1), Duration::from_millis(10));
+    t!(utun.set_mtu(1400));
+    t!(utun.set_ipv4_address(Ipv4Addr::new(10, 44, 0, 1), Ipv4Addr::new(255, 255, 255, 0)));
+    t!(utun.up());
+
+    let poll = t!(Poll::new());
+    t!(poll.register(&utun, Token(7), Ready::readable(), PollOpt::edge()));
+
+    let status = t!(Command::new("ip").args(["link", "del", "utun44"]).status());
+    assert!(status.success());
+
+    // Reconnect, then drain whatever the kernel sent on bringing it up.
+    let mut buf = [0u8; 1500];
+    loop {
+        match utun.read(&mut buf) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => panic!("read failed with {}", e),
+        }
+    }
+
+    let stream = utun.get_ref();
+    assert_eq!(t!(stream.mtu()), 1400);
+    assert!(t!(stream.addresses()).contains(&IpAddr::V4(Ipv4Addr::new(10, 44, 0, 1))));
+    assert_ne!(t!(stream.flags()) & mio_utun::linux::IFF_UP, 0);
+
+    let socket = t!(UdpSocket::bind("10.44.0.1:0"));
+    t!(socket.send_to(b"ping", "10.44.0.2:9"));
+
+    // The poll can wake up for readiness that was cleared again, so keep
+    // polling until the event arrives.
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    let mut events = Events::with_capacity(4);
+    while events.is_empty() {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        assert!(remaining > Duration::from_secs(0), "no event after reconnecting");
+        t!(poll.poll(&mut events, Some(remaining)));
+    }
+    let event = events.iter().next().unwrap();
+    assert_eq!(event.token(), Token(7));
+    assert!(event.readiness().is_readable());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reconnecting_reports_read_after_write() {
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::time::Instant;
+    use mio_utun::ReconnectingUtun;
+
+    let mut utun = t!(ReconnectingUtun::new(UtunBuilder::new("utun45")));
+    // No IPv6, so that the packet below is the first the kernel sends.
+    t!(std::fs::write("/proc/sys/net/ipv6/conf/utun45/disable_ipv6", "1"));
+    t!(utun.set_ipv4_address(Ipv4Addr::new(10, 45, 0, 1), Ipv4Addr::new(255, 255, 255, 0)));
+    t!(utun.up());
+
+    let poll = t!(Poll::new());
+    t!(poll.register(&utun, Token(8), Ready::readable() | Ready::writable(), PollOpt::edge()));
+
+    // Wait for the first event with `ready` set, failing after 5s.
+    let mut events = Events::with_capacity(4);
+    let mut wait_for = |ready: Ready| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            assert!(remaining > Duration::from_secs(0), "no {:?} event", ready);
+            t!(poll.poll(&mut events, Some(remaining)));
+            if events.iter().any(|e| e.token() == Token(8) && e.readiness().contains(ready)) {
+                return;
+            }
+        }
+    };
+
+    // Nothing is read, so nothing would block and re-arm the fd.
+    wait_for(Ready::writable());
+
+    let socket = t!(UdpSocket::bind("10.45.0.1:0"));
+    t!(socket.send_to(b"ping", "10.45.0.2:9"));
+    wait_for(Ready::readable());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn setup_restores_persistent_interface() {
+    use std::process::Command;
+    use std::net::Ipv4Addr;
+
+    let ip = |args: &[&str]| assert!(t!(Command::new("ip").args(args).status()).success());
+    ip(&["tuntap", "add", "dev", "utun46", "mode", "tun", "pi"]);
+
+    // The kernel takes the address, then refuses the non-contiguous netmask.
+    let res = UtunStream::setup("utun46", Ipv4Addr::new(10, 46, 0, 1), Ipv4Addr::new(255, 0, 255, 0), 1400);
+    assert!(res.is_err());
+
+    let utun = t!(UtunStream::connect("utun46"));
+    let (mtu, addresses, flags) = (t!(utun.mtu()), t!(utun.addresses()), t!(utun.flags()));
+    drop(utun);
+    ip(&["tuntap", "del", "dev", "utun46", "mode", "tun", "pi"]);
+    assert_eq!(mtu, 1500);
+    assert!(addresses.is_empty());
+    assert_eq!(flags & mio_utun::linux::IFF_UP, 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn tap_discards_frames() {
+    let mut tap = t!(UtunBuilder::new("utap3").tap(true).connect());
+    t!(tap.up());
+    tap.set_discard_writes(true);
+
+    let mut frame = [0u8; 64];
+    frame[..6].copy_from_slice(&[0xff; 6]);
+    frame[12..14].copy_from_slice(&[0x08, 0x06]);
+    assert_eq!(t!(tap.send_ethernet(&frame)), 64);
+    assert_eq!(t!(tap.send_ethernet_with_fcs(&frame)), 60);
+    assert_eq!(tap.stats().tx_packets, 0);
+}
@@ -0,0 +1,393 @@
+#![cfg(feature = "std")]
+
+extern crate libc;
+extern crate mio_utun;
+extern crate nix;
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant, SystemTime};
+
+use mio_utun::{header_for, macos_header_family, queue_stats, Budget, DecapReader, IpVersion, QueueStat,
+                RateLimited, RecvInfo, UtunStream, HEADER_LEN};
+use nix::sys::socket::AddressFamily;
+
+/// Returns a stream backed by one end of a datagram socketpair, with the other
+/// end standing in for the kernel side of the tunnel.
+fn pair() -> (UtunStream, UnixDatagram) {
+    let (ours, theirs) = UnixDatagram::pair().unwrap();
+    let stream = unsafe { UtunStream::from_raw_fd(ours.into_raw_fd()) };
+    (stream, theirs)
+}
+
+#[test]
+fn decap_strips_header() {
+    let (stream, kernel) = pair();
+    kernel.send(&[0, 0, 0x08, 0x00, 0x45, 1, 2, 3]).unwrap();
+
+    let mut buf = [0u8; 64];
+    let len = DecapReader(&stream).read(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &[0x45, 1, 2, 3]);
+}
+
+#[test]
+fn lenprefixed_leaves_partial_frame() {
+    let (stream, kernel) = pair();
+    let framed = [0, 2, 0x45, 1,
+                  0, 3, 0x60, 2, 3,
+                  0, 9, 0x45];
+    assert_eq!(stream.write_lenprefixed(&framed).unwrap(), 2);
+
+    let mut buf = [0u8; 64];
+    let len = kernel.recv(&mut buf).unwrap();
+    assert_eq!(&buf[4..len], &[0x45, 1]);
+    let len = kernel.recv(&mut buf).unwrap();
+    assert_eq!(&buf[4..len], &[0x60, 2, 3]);
+}
+
+#[test]
+fn counts_packets() {
+    let (mut stream, kernel) = pair();
+    stream.write_lenprefixed(&[0, 1, 0x45, 0, 1, 0x60]).unwrap();
+    kernel.send(&[0, 0, 0, 0, 0x45]).unwrap();
+    assert_eq!(stream.read(&mut [0u8; 64]).unwrap(), 5);
+
+    let stats = queue_stats(&[stream]).unwrap();
+    assert_eq!(stats, vec![QueueStat { rx_packets: 1, tx_packets: 2 }]);
+}
+
+#[test]
+fn self_test() {
+    mio_utun::self_test().unwrap();
+}
+
+#[test]
+fn custom_prefix() {
+    let (stream, kernel) = pair();
+    assert_eq!(stream.write_with_prefix([0xde, 0xad, 0xbe, 0xef], &[1, 2, 3]).unwrap(), 3);
+
+    let mut buf = [0u8; 64];
+    let len = kernel.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &[0xde, 0xad, 0xbe, 0xef, 1, 2, 3]);
+}
+
+#[test]
+fn checked_adoption_rejects_sockets() {
+    let (ours, _theirs) = UnixDatagram::pair().unwrap();
+    let err = UtunStream::from_owned_fd_checked(OwnedFd::from(ours)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn decodes_macos_header() {
+    assert_eq!(macos_header_family(&[0, 0, 0, 2, 0x45]), Some(AddressFamily::Inet));
+    assert_eq!(macos_header_family(&[0, 0, 0, 30]), Some(AddressFamily::Inet6));
+    assert_eq!(macos_header_family(&[0, 0, 0x08, 0x00]), None);
+    assert_eq!(macos_header_family(&[0, 0, 0]), None);
+}
+
+#[test]
+fn core_framing() {
+    use mio_utun::framing::{self, HeaderFormat};
+
+    assert_eq!(framing::ip_version(&[0x45, 0]), Some(IpVersion::V4));
+    assert_eq!(framing::ip_version(&[0x60]), Some(IpVersion::V6));
+    assert_eq!(framing::ip_version(&[0x10]), None);
+    assert_eq!(framing::ip_version(&[]), None);
+
+    let header = framing::header_for(HeaderFormat::AddressFamily, IpVersion::V6);
+    assert_eq!(macos_header_family(&header), Some(AddressFamily::Inet6));
+    assert_eq!(framing::header_for(HeaderFormat::PacketInfo, IpVersion::V4), [0, 0, 0x08, 0x00]);
+    assert_eq!(framing::header_for(HeaderFormat::NATIVE, IpVersion::V4), header_for(IpVersion::V4));
+
+    assert_eq!(framing::strip_header(&[0, 0, 0x08, 0x00, 0x45]), Some(&[0x45][..]));
+    assert_eq!(framing::strip_header(&[0, 0, 0x08]), None);
+}
+
+#[test]
+fn discards_writes() {
+    let (mut stream, kernel) = pair();
+    stream.set_discard_writes(true);
+    assert_eq!(stream.write(&[0x45, 0, 0, 0]).unwrap(), 4);
+    assert_eq!(stream.write_with_prefix([0xde, 0xad, 0xbe, 0xef], &[1, 2, 3]).unwrap(), 3);
+
+    kernel.set_nonblocking(true).unwrap();
+    assert!(kernel.recv(&mut [0u8; 64]).is_err());
+}
+
+#[test]
+fn timestamps_reads() {
+    let (stream, kernel) = pair();
+    let before = SystemTime::now() - Duration::from_secs(1);
+    kernel.send(&[0, 0, 0x08, 0x00, 0x45, 1, 2, 3]).unwrap();
+
+    let mut buf = [0u8; 64];
+    let (len, time) = stream.recv_timestamped(&mut buf).unwrap();
+    assert_eq!(len, 8);
+    assert!(time >= before && time <= SystemTime::now());
+}
+
+#[test]
+fn writes_forced_version() {
+    let (stream, kernel) = pair();
+    assert_eq!(stream.write_as(IpVersion::V6, &[0x45, 1, 2]).unwrap(), 3);
+
+    let mut buf = [0u8; 64];
+    let len = kernel.recv(&mut buf).unwrap();
+    #[cfg(target_os = "linux")]
+    let header = [0, 0, 0x86, 0xdd];
+    #[cfg(not(target_os = "linux"))]
+    let header = [0, 0, 0, 0x1e];
+    assert_eq!(&buf[..4], &header);
+    assert_eq!(&buf[4..len], &[0x45, 1, 2]);
+}
+
+#[test]
+fn header_for_matches_write() {
+    let (stream, kernel) = pair();
+    stream.write_as(IpVersion::V4, &[0x45, 1]).unwrap();
+
+    let mut buf = [0u8; 64];
+    let len = kernel.recv(&mut buf).unwrap();
+    assert_eq!(len, HEADER_LEN + 2);
+    assert_eq!(&buf[..HEADER_LEN], &header_for(IpVersion::V4));
+}
+
+#[test]
+fn reports_closed_fd() {
+    let stream = unsafe { UtunStream::from_raw_fd(-1) };
+    let err = (&stream).read(&mut [0u8; 64]).unwrap_err();
+    assert_eq!(err.to_string(), "tunnel fd is closed or invalid");
+    assert_eq!(stream.into_raw_fd(), -1);
+}
+
+#[test]
+fn no_pending_error() {
+    let (stream, _kernel) = pair();
+    assert!(stream.take_error().unwrap().is_none());
+}
+
+#[test]
+fn rate_limited_would_block() {
+    let (ours, _kernel) = UnixDatagram::pair().unwrap();
+    ours.set_nonblocking(true).unwrap();
+    let stream = unsafe { UtunStream::from_raw_fd(ours.into_raw_fd()) };
+    let mut limited = RateLimited::new(stream, Budget::PacketsPerSecond(2)).unwrap();
+
+    assert_eq!(limited.write(&[0x45, 1]).unwrap(), 2);
+    assert_eq!(limited.write(&[0x45, 2]).unwrap(), 2);
+    assert_eq!(limited.write(&[0x45, 3]).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    assert_eq!(limited.get_ref().stats().tx_packets, 2);
+}
+
+#[test]
+fn rate_limited_blocks() {
+    let (stream, _kernel) = pair();
+    let mut limited = RateLimited::new(stream, Budget::BytesPerSecond(100)).unwrap();
+
+    let start = Instant::now();
+    limited.write_all(&[0x45; 100]).unwrap();
+    limited.write_all(&[0x45; 10]).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(90));
+}
+
+#[test]
+fn rate_limit_rejects_zero_budget() {
+    let (stream, _kernel) = pair();
+    let err = RateLimited::new(stream, Budget::PacketsPerSecond(0)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn detailed_recv() {
+    let (stream, kernel) = pair();
+    kernel.send(&[0, 0, 0x86, 0xdd, 0x60, 1, 2, 3]).unwrap();
+
+    let mut buf = [0u8; 64];
+    let info = stream.recv_detailed(&mut buf).unwrap();
+    assert_eq!(info, RecvInfo {
+        payload_len: 4,
+        header_len: 4,
+        truncated: false,
+        version: Some(IpVersion::V6),
+    });
+    assert_eq!(&buf[..4], &[0x60, 1, 2, 3]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn detailed_recv_truncated() {
+    let (stream, kernel) = pair();
+    let strip = mio_utun::linux::TUN_PKT_STRIP.to_ne_bytes();
+    kernel.send(&[strip[0], strip[1], 0x08, 0x00, 0x45, 1]).unwrap();
+
+    let mut buf = [0u8; 2];
+    assert!(stream.recv_detailed(&mut buf).unwrap().truncated);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn epoll_flags() {
+    use mio_utun::linux::epoll_events;
+
+    assert_eq!(epoll_events(true, false), (libc::EPOLLIN | libc::EPOLLET) as u32);
+    assert_eq!(epoll_events(true, true), (libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLET) as u32);
+}
+
+#[test]
+fn lenient_version_check() {
+    let (mut stream, kernel) = pair();
+    assert!(stream.write(&[0x10, 1]).is_err());
+
+    stream.set_strict_version_check(false);
+    assert_eq!(stream.write(&[0x10, 1]).unwrap(), 2);
+    let mut buf = [0u8; 64];
+    let len = kernel.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &[&header_for(IpVersion::V4)[..], &[0x10, 1]].concat()[..]);
+}
+
+#[test]
+fn unknown_packet_policy() {
+    use mio_utun::UnknownPacketPolicy;
+
+    let (mut stream, kernel) = pair();
+    stream.set_unknown_policy(UnknownPacketPolicy::Drop);
+    assert_eq!(stream.write(&[0x10, 1]).unwrap(), 0);
+    assert_eq!(stream.stats().tx_packets, 0);
+
+    stream.set_unknown_policy(UnknownPacketPolicy::Assume(IpVersion::V6));
+    assert_eq!(stream.write(&[0x10, 1]).unwrap(), 2);
+    let mut buf = [0u8; 64];
+    let len = kernel.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &[&header_for(IpVersion::V6)[..], &[0x10, 1]].concat()[..]);
+
+    stream.set_unknown_policy(UnknownPacketPolicy::Error);
+    assert!(stream.write(&[0x10, 1]).is_err());
+}
+
+#[test]
+fn adopts_owned_fd() {
+    use std::os::unix::io::OwnedFd;
+
+    let (ours, kernel) = UnixDatagram::pair().unwrap();
+    let stream = UtunStream::from_owned_fd(OwnedFd::from(ours));
+    kernel.send(&[0, 0, 0x08, 0x00, 0x45]).unwrap();
+    assert_eq!(DecapReader(&stream).read(&mut [0u8; 64]).unwrap(), 1);
+
+    let fd = OwnedFd::from(stream);
+    assert!(fd.as_raw_fd() >= 0);
+}
+
+#[test]
+fn writes_into_scratch() {
+    let (stream, kernel) = pair();
+    let mut scratch = Vec::new();
+    assert_eq!(stream.write_into(&mut scratch, &[0x45, 1, 2]).unwrap(), 3);
+    assert_eq!(stream.write_into(&mut scratch, &[0x60, 3]).unwrap(), 2);
+    assert_eq!(&scratch[..], &[&header_for(IpVersion::V6)[..], &[0x60, 3]].concat()[..]);
+
+    let mut buf = [0u8; 64];
+    let len = kernel.recv(&mut buf).unwrap();
+    assert_eq!(&buf[HEADER_LEN..len], &[0x45, 1, 2]);
+}
+
+#[test]
+fn sends_keepalive() {
+    let (mut stream, kernel) = pair();
+    assert_eq!(stream.send_keepalive().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    stream.set_keepalive("10.0.0.1:4500".parse().unwrap(), "10.0.0.2:4500".parse().unwrap());
+    stream.send_keepalive().unwrap();
+    let mut buf = [0u8; 64];
+    let len = kernel.recv(&mut buf).unwrap();
+    assert_eq!(len, HEADER_LEN + 28);
+    assert_eq!(buf[HEADER_LEN + 9], 17);
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[test]
+fn utun_units() {
+    use mio_utun::macos::{ifnum_for_unit, utun_unit_for};
+
+    assert_eq!(utun_unit_for(0), Some(1));
+    assert_eq!(utun_unit_for(5), Some(6));
+    assert_eq!(utun_unit_for(u32::MAX), None);
+    assert_eq!(utun_unit_for(7).and_then(ifnum_for_unit), Some(7));
+    assert_eq!(ifnum_for_unit(0), None);
+
+    let err = mio_utun::UtunBuilder::new("utun4294967295").connect().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[test]
+fn kqueue_filters() {
+    assert_eq!(mio_utun::macos::kqueue_filters(), (libc::EVFILT_READ, libc::EVFILT_WRITE));
+}
+
+#[test]
+fn forwards_with_ttl() {
+    let (stream, kernel) = pair();
+    let mut packet = [0x60, 0, 0, 0, 0, 0, 59, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                      0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    assert_eq!(stream.write_forwarded(&mut packet).unwrap(), 0);
+
+    packet[7] = 64;
+    assert_eq!(stream.write_forwarded(&mut packet).unwrap(), 40);
+    let mut buf = [0u8; 64];
+    let len = kernel.recv(&mut buf).unwrap();
+    assert_eq!(len, HEADER_LEN + 40);
+    assert_eq!(buf[HEADER_LEN + 7], 63);
+}
+
+#[test]
+fn descriptor_round_trip() {
+    use mio_utun::TunnelDescriptor;
+
+    let desc = TunnelDescriptor {
+        name: "utun3".to_string(),
+        mtu: 1400,
+        addresses: vec!["10.0.0.1".parse().unwrap(), "fe80::1".parse().unwrap()],
+        flags: 0x51,
+        packet_info: true,
+    };
+    let line = desc.to_string();
+    assert_eq!(line, "name=utun3 mtu=1400 flags=0x51 packet_info=1 addresses=10.0.0.1,fe80::1");
+    assert_eq!(line.parse::<TunnelDescriptor>().unwrap(), desc);
+
+    let bare = "name=utun3 mtu=1400 flags=0x0 packet_info=0 addresses=";
+    assert!(bare.parse::<TunnelDescriptor>().unwrap().addresses.is_empty());
+    let err = "name=utun3 mtu=big flags=0x0 packet_info=0 addresses=".parse::<TunnelDescriptor>();
+    assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert!("name=utun3 mtu=1400".parse::<TunnelDescriptor>().is_err());
+}
+
+#[test]
+fn validates_names() {
+    use mio_utun::validate_name;
+
+    assert!(validate_name("utun3").is_ok());
+    assert!(validate_name("fifteen-chars-x").is_ok());
+    for name in &["", "sixteen-chars-xx", "utun\0", "tün0"] {
+        assert_eq!(validate_name(name).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+    assert_eq!(UtunStream::connect("").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn split_read() {
+    let (stream, kernel) = pair();
+    kernel.send(&[0, 0, 0, 2, 0x45, 1, 2]).unwrap();
+
+    let mut header = [0u8; HEADER_LEN];
+    let mut payload = [0u8; 64];
+    assert_eq!(stream.recv_split(&mut header, &mut payload).unwrap(), 3);
+    assert_eq!(header, [0, 0, 0, 2]);
+    assert_eq!(&payload[..3], &[0x45, 1, 2]);
+    assert_eq!(stream.stats().rx_packets, 1);
+
+    kernel.send(&[0, 0]).unwrap();
+    let err = stream.recv_split(&mut header, &mut payload).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
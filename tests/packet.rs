@@ -0,0 +1,133 @@
+#![cfg(feature = "std")]
+
+extern crate mio_utun;
+
+use mio_utun::packet::{checksum, decrement_ttl, fragment_ipv4, segment_tcp, udp_packet};
+
+/// Returns an IPv4 packet with a valid header and `payload_len` bytes of
+/// payload.
+fn ipv4(payload_len: usize) -> Vec<u8> {
+    let len = 20 + payload_len;
+    let mut packet = vec![0x45, 0, (len >> 8) as u8, len as u8,
+                          0x12, 0x34, 0, 0, 64, 17, 0, 0,
+                          10, 0, 0, 1, 10, 0, 0, 2];
+    packet.extend((0..payload_len).map(|i| i as u8));
+    let sum = checksum(&packet[..20]);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet
+}
+
+#[test]
+fn checksum_verifies() {
+    let packet = ipv4(8);
+    assert_eq!(checksum(&packet[..20]), 0);
+}
+
+#[test]
+fn fragments_ipv4() {
+    let packet = ipv4(100);
+    let fragments = fragment_ipv4(&packet, 60).unwrap();
+    assert_eq!(fragments.len(), 3);
+
+    let mut payload = Vec::new();
+    for (i, fragment) in fragments.iter().enumerate() {
+        assert!(fragment.len() <= 60);
+        assert_eq!(checksum(&fragment[..20]), 0);
+        assert_eq!(u16::from_be_bytes([fragment[2], fragment[3]]) as usize, fragment.len());
+
+        let frag = u16::from_be_bytes([fragment[6], fragment[7]]);
+        assert_eq!((frag & 0x1fff) as usize * 8, payload.len());
+        assert_eq!(frag & 0x2000 != 0, i < 2);
+        payload.extend_from_slice(&fragment[20..]);
+    }
+    assert_eq!(payload, &packet[20..]);
+}
+
+#[test]
+fn refuses_dont_fragment() {
+    let mut packet = ipv4(100);
+    packet[6] = 0x40;
+    assert!(fragment_ipv4(&packet, 60).is_err());
+}
+
+#[test]
+fn builds_udp_packets() {
+    let v4 = udp_packet("10.0.0.1:1234".parse().unwrap(), "10.0.0.2:53".parse().unwrap(), b"hi").unwrap();
+    assert_eq!(v4.len(), 30);
+    assert_eq!(checksum(&v4[..20]), 0);
+    assert_eq!(&v4[20..24], &[0x04, 0xd2, 0, 53]);
+
+    let v6 = udp_packet("[fd00::1]:1".parse().unwrap(), "[fd00::2]:2".parse().unwrap(), &[]).unwrap();
+    assert_eq!(v6.len(), 48);
+    assert_eq!(v6[6], 17);
+    assert_ne!(&v6[46..48], &[0, 0]);
+
+    assert!(udp_packet("10.0.0.1:1".parse().unwrap(), "[fd00::2]:2".parse().unwrap(), &[]).is_err());
+}
+
+/// Returns an IPv4 TCP packet with sequence number 1000, the FIN, PSH and ACK
+/// flags, and `payload_len` bytes of payload.
+fn tcp_ipv4(payload_len: usize) -> Vec<u8> {
+    let len = 40 + payload_len;
+    let mut packet = vec![0x45, 0, (len >> 8) as u8, len as u8,
+                          0x12, 0x34, 0x40, 0, 64, 6, 0, 0,
+                          10, 0, 0, 1, 10, 0, 0, 2,
+                          0x04, 0xd2, 0, 80, 0, 0, 0x03, 0xe8,
+                          0, 0, 0, 0, 0x50, 0x19, 0xff, 0xff,
+                          0, 0, 0, 0];
+    packet.extend((0..payload_len).map(|i| i as u8));
+    packet
+}
+
+#[test]
+fn segments_tcp() {
+    let packet = tcp_ipv4(250);
+    let segments = segment_tcp(&packet, 100).unwrap();
+    assert_eq!(segments.iter().map(|s| s.len()).collect::<Vec<_>>(), vec![140, 140, 90]);
+
+    for (i, segment) in segments.iter().enumerate() {
+        assert_eq!(checksum(&segment[..20]), 0);
+        let mut pseudo = segment[12..20].to_vec();
+        pseudo.extend_from_slice(&[0, 6, 0, (segment.len() - 20) as u8]);
+        pseudo.extend_from_slice(&segment[20..]);
+        assert_eq!(checksum(&pseudo), 0);
+        let seq = u32::from_be_bytes([segment[24], segment[25], segment[26], segment[27]]);
+        assert_eq!(seq, 1000 + 100 * i as u32);
+        assert_eq!(&segment[40..], &packet[40 + 100 * i..(40 + 100 * (i + 1)).min(packet.len())]);
+    }
+    assert_eq!(segments[0][33], 0x10);
+    assert_eq!(segments[2][33], 0x19);
+
+    assert!(segment_tcp(&ipv4(8), 100).is_err());
+}
+
+#[test]
+fn segments_tcp_with_ip_options() {
+    // IHL 10: 20 bytes of no-op options between the IP and TCP headers.
+    let mut packet = tcp_ipv4(250);
+    packet[0] = 0x4a;
+    let total_len = (packet.len() + 20) as u16;
+    packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+    packet.splice(20..20, vec![1u8; 20]);
+
+    let segments = segment_tcp(&packet, 100).unwrap();
+    assert_eq!(segments.iter().map(|s| s.len()).collect::<Vec<_>>(), vec![160, 160, 110]);
+    for (i, segment) in segments.iter().enumerate() {
+        assert_eq!(u16::from_be_bytes([segment[2], segment[3]]) as usize, segment.len());
+        assert_eq!(u16::from_be_bytes([segment[4], segment[5]]), 0x1234 + i as u16);
+        assert_eq!(checksum(&segment[..40]), 0);
+        assert_eq!(&segment[20..40], &[1u8; 20][..]);
+    }
+}
+
+#[test]
+fn decrements_ttl() {
+    let mut packet = ipv4(8);
+    assert!(decrement_ttl(&mut packet).unwrap());
+    assert_eq!(packet[8], 63);
+    assert_eq!(checksum(&packet[..20]), 0);
+
+    packet[8] = 1;
+    assert!(!decrement_ttl(&mut packet).unwrap());
+    assert_eq!(packet[8], 1);
+}